@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Lets another thread, e.g. a Ctrl-C handler, stop the generation in progress.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    active: Arc<AtomicBool>,
+}
+
+/// Marks a generation as in progress until dropped.
+pub struct ActiveGeneration {
+    token: CancelToken,
+}
+
+impl Drop for ActiveGeneration {
+    fn drop(&mut self) {
+        self.token.active.store(false, Ordering::SeqCst);
+    }
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests the generation in progress to stop, returns `false` when no
+    /// generation is running.
+    pub fn cancel(&self) -> bool {
+        if !self.active.load(Ordering::SeqCst) {
+            return false;
+        }
+        self.cancelled.store(true, Ordering::SeqCst);
+        true
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Clears any previous cancellation and marks a generation as started.
+    pub fn start(&self) -> ActiveGeneration {
+        self.cancelled.store(false, Ordering::SeqCst);
+        self.active.store(true, Ordering::SeqCst);
+        ActiveGeneration {
+            token: self.clone(),
+        }
+    }
+}
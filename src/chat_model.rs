@@ -0,0 +1,176 @@
+use anyhow::Result;
+
+use candle_core::{DType, Device, Tensor};
+use candle_transformers::models::{gemma, llama, mistral, phi3, quantized_mistral, qwen2};
+
+use crate::quantized;
+
+/// What the generation needs to know about a loaded model.
+#[derive(Clone, Debug)]
+pub struct ModelConfig {
+    pub vocab_size: usize,
+    pub max_position_embeddings: usize,
+    /// Text of the BOS token, rendered at the start of the prompts.
+    pub bos_token: String,
+    /// Text of the EOS token closing the assistant turns of the prompts.
+    pub eos_token: String,
+    /// Tokens ending the generation.
+    pub eos_token_ids: Vec<u32>,
+}
+
+/// A causal language model with a KV cache.
+pub trait ChatModel {
+    /// Runs the model on `input` whose first token sits at position
+    /// `seqlen_offset`, returns the logits for the last position with shape
+    /// `(batch, 1, vocab_size)`.
+    fn forward(&mut self, input: &Tensor, seqlen_offset: usize) -> Result<Tensor>;
+
+    fn clear_kv_cache(&mut self);
+
+    fn config(&self) -> &ModelConfig;
+}
+
+/// A candle model along with its configuration.
+pub struct WithConfig<M> {
+    pub model: M,
+    pub config: ModelConfig,
+}
+
+/// Implements `ChatModel` for the candle models whose `forward` and
+/// `clear_kv_cache` already match it.
+macro_rules! chat_model {
+    ($($model:ty),*) => {
+        $(
+            impl ChatModel for WithConfig<$model> {
+                // Some of the models already return an `anyhow::Result`.
+                #[allow(clippy::needless_question_mark)]
+                fn forward(&mut self, input: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+                    Ok(self.model.forward(input, seqlen_offset)?)
+                }
+
+                fn clear_kv_cache(&mut self) {
+                    self.model.clear_kv_cache()
+                }
+
+                fn config(&self) -> &ModelConfig {
+                    &self.config
+                }
+            }
+        )*
+    };
+}
+
+chat_model!(
+    mistral::Model,
+    quantized::Model,
+    quantized_mistral::Model,
+    qwen2::ModelForCausalLM,
+    phi3::Model,
+    gemma::Model
+);
+
+/// Llama keeps its KV cache outside of the model.
+pub struct Llama {
+    model: llama::Llama,
+    cache: llama::Cache,
+    /// Cleared cache, holding the rotary embedding tables.
+    empty_cache: llama::Cache,
+    config: ModelConfig,
+}
+
+impl Llama {
+    pub fn new(
+        model: llama::Llama,
+        llama_config: &llama::Config,
+        dtype: DType,
+        device: &Device,
+        config: ModelConfig,
+    ) -> Result<Self> {
+        let empty_cache = llama::Cache::new(true, dtype, llama_config, device)?;
+        Ok(Self {
+            model,
+            cache: empty_cache.clone(),
+            empty_cache,
+            config,
+        })
+    }
+}
+
+impl ChatModel for Llama {
+    fn forward(&mut self, input: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let seq_len = input.dim(1)?;
+        // The attention mask of candle's Llama only spans the new tokens, it
+        // cannot cover the cached ones, so the tokens following them are
+        // processed one at a time.
+        let step = if seqlen_offset > 0 { 1 } else { seq_len };
+        let cache = &mut self.cache;
+        let first = input.narrow(1, 0, step)?;
+        let mut logits = self.model.forward(&first, seqlen_offset, cache)?;
+        for i in step..seq_len {
+            let token = input.narrow(1, i, 1)?;
+            logits = self.model.forward(&token, seqlen_offset + i, cache)?;
+        }
+        Ok(logits.unsqueeze(1)?)
+    }
+
+    fn clear_kv_cache(&mut self) {
+        self.cache = self.empty_cache.clone()
+    }
+
+    fn config(&self) -> &ModelConfig {
+        &self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_nn::VarBuilder;
+
+    fn llama() -> Llama {
+        let llama_config: llama::LlamaConfig = serde_json::from_value(serde_json::json!({
+            "hidden_size": 16,
+            "intermediate_size": 32,
+            "vocab_size": 50,
+            "num_hidden_layers": 2,
+            "num_attention_heads": 2,
+            "rms_norm_eps": 1e-5,
+            "max_position_embeddings": 64,
+        }))
+        .unwrap();
+        let llama_config = llama_config.into_config(false);
+        let device = Device::Cpu;
+        let vb = VarBuilder::zeros(DType::F32, &device);
+        let model = llama::Llama::load(vb, &llama_config).unwrap();
+        let config = ModelConfig {
+            vocab_size: 50,
+            max_position_embeddings: 64,
+            bos_token: String::new(),
+            eos_token: String::new(),
+            eos_token_ids: vec![],
+        };
+        Llama::new(model, &llama_config, DType::F32, &device, config).unwrap()
+    }
+
+    fn input(tokens: &[u32]) -> Tensor {
+        Tensor::new(tokens, &Device::Cpu)
+            .unwrap()
+            .unsqueeze(0)
+            .unwrap()
+    }
+
+    #[test]
+    fn llama_extends_the_cache() {
+        let mut model = llama();
+        let logits = model.forward(&input(&[1, 2, 3]), 0).unwrap();
+        assert_eq!(logits.dims(), [1, 1, 50]);
+        // The next turn starts after the cached tokens.
+        let logits = model.forward(&input(&[4, 5, 6]), 3).unwrap();
+        assert_eq!(logits.dims(), [1, 1, 50]);
+        let logits = model.forward(&input(&[7]), 6).unwrap();
+        assert_eq!(logits.dims(), [1, 1, 50]);
+        model.clear_kv_cache();
+        let logits = model.forward(&input(&[1, 2]), 0).unwrap();
+        assert_eq!(logits.dims(), [1, 1, 50]);
+    }
+}
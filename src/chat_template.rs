@@ -0,0 +1,293 @@
+use anyhow::{Error as E, Result};
+
+use crate::conversation::{Conversation, Role};
+
+/// The prompt format a model was fine-tuned on.
+#[derive(Clone, Debug)]
+pub enum ChatTemplate {
+    /// Plain `User:`/`Assistant:` transcript, for the base models.
+    Plain,
+    /// `[INST] ... [/INST]` with spaces, as used by Mistral 7B Instruct.
+    MistralInstruct,
+    /// `[INST]...[/INST]` without spaces, as used by Mistral Nemo Instruct.
+    MistralNemo,
+    /// `<|start_header_id|>` headers, as used by Llama 3.
+    Llama3,
+    /// `<|im_start|>`/`<|im_end|>` ChatML turns, as used by Qwen2.
+    ChatMl,
+    /// `<|user|>`/`<|assistant|>` turns, as used by Phi-3.
+    Phi3,
+    /// `<start_of_turn>` turns without a system role, as used by Gemma.
+    Gemma,
+    /// A Jinja `chat_template` taken from `tokenizer_config.json`.
+    Jinja(String),
+}
+
+impl ChatTemplate {
+    /// The built-in template with the given registry name.
+    pub fn from_name(name: &str) -> Result<Self> {
+        let template = match name {
+            "plain" => Self::Plain,
+            "mistral-instruct" => Self::MistralInstruct,
+            "mistral-nemo" => Self::MistralNemo,
+            "llama3" => Self::Llama3,
+            "chatml" => Self::ChatMl,
+            "phi3" => Self::Phi3,
+            "gemma" => Self::Gemma,
+            _ => anyhow::bail!("unknown chat template '{name}'"),
+        };
+        Ok(template)
+    }
+
+    /// Text that marks the end of the assistant turn besides the EOS token.
+    pub fn stop_sequences(&self) -> Vec<String> {
+        match self {
+            // Base models happily carry on and write the next user turn.
+            Self::Plain => vec!["\nUser:".to_string()],
+            Self::MistralInstruct | Self::MistralNemo => vec!["[INST]".to_string()],
+            // The turns of the other templates end with an EOS token.
+            Self::Llama3 | Self::ChatMl | Self::Phi3 | Self::Gemma | Self::Jinja(_) => vec![],
+        }
+    }
+
+    /// Reads the `chat_template` entry of a `tokenizer_config.json` file, if any.
+    pub fn from_tokenizer_config<P: AsRef<std::path::Path>>(path: P) -> Result<Option<Self>> {
+        let config: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+        let template = match config.get("chat_template") {
+            Some(serde_json::Value::String(template)) => Some(template.clone()),
+            // Some configs ship a list of named templates, use the default one.
+            Some(serde_json::Value::Array(templates)) => templates
+                .iter()
+                .find(|t| t.get("name").and_then(|n| n.as_str()) == Some("default"))
+                .and_then(|t| t.get("template"))
+                .and_then(|t| t.as_str())
+                .map(|t| t.to_string()),
+            _ => None,
+        };
+        Ok(template.map(Self::Jinja))
+    }
+
+    /// Renders the conversation into the text the model expects, ending with
+    /// an open assistant turn. The output starts with the BOS token so it must
+    /// be encoded without adding special tokens.
+    pub fn render(&self, conversation: &Conversation, bos: &str, eos: &str) -> Result<String> {
+        match self {
+            Self::Plain => Ok(format!("{bos}{}", conversation.prompt())),
+            Self::MistralInstruct => Ok(render_inst(conversation, bos, eos, " ")),
+            Self::MistralNemo => Ok(render_inst(conversation, bos, eos, "")),
+            Self::Llama3 => Ok(render_turns(
+                conversation,
+                bos,
+                |role, content| {
+                    format!("<|start_header_id|>{role}<|end_header_id|>\n\n{content}<|eot_id|>")
+                },
+                "<|start_header_id|>assistant<|end_header_id|>\n\n",
+            )),
+            Self::ChatMl => Ok(render_turns(
+                conversation,
+                bos,
+                |role, content| format!("<|im_start|>{role}\n{content}<|im_end|>\n"),
+                "<|im_start|>assistant\n",
+            )),
+            Self::Phi3 => Ok(render_turns(
+                conversation,
+                bos,
+                |role, content| format!("<|{role}|>\n{content}<|end|>\n"),
+                "<|assistant|>\n",
+            )),
+            Self::Gemma => Ok(render_gemma(conversation, bos)),
+            Self::Jinja(source) => render_jinja(source, conversation, bos, eos),
+        }
+    }
+}
+
+/// Mistral instruct models have no system role, the system prompt is folded
+/// into the first user turn instead.
+fn render_inst(conversation: &Conversation, bos: &str, eos: &str, sep: &str) -> String {
+    let mut system = None;
+    let mut prompt = bos.to_string();
+    for message in conversation.messages().iter() {
+        match message.role {
+            Role::System => system = Some(message.content.as_str()),
+            Role::User => {
+                let content = match system.take() {
+                    Some(system) => format!("{system}\n\n{}", message.content),
+                    None => message.content.clone(),
+                };
+                prompt.push_str(&format!("[INST]{sep}{content}{sep}[/INST]"));
+            }
+            Role::Assistant => prompt.push_str(&format!("{sep}{}{eos}", message.content)),
+        }
+    }
+    prompt
+}
+
+/// Renders every message with `turn`, given the role name and the content,
+/// then opens the assistant turn with `open`.
+fn render_turns<F>(conversation: &Conversation, bos: &str, turn: F, open: &str) -> String
+where
+    F: Fn(&str, &str) -> String,
+{
+    let mut prompt = bos.to_string();
+    for message in conversation.messages().iter() {
+        prompt.push_str(&turn(message.role.as_str(), &message.content));
+    }
+    prompt.push_str(open);
+    prompt
+}
+
+/// Gemma has no system role and calls the assistant `model`, the system
+/// prompt is folded into the first user turn as for Mistral.
+fn render_gemma(conversation: &Conversation, bos: &str) -> String {
+    let mut system = None;
+    let mut prompt = bos.to_string();
+    for message in conversation.messages().iter() {
+        match message.role {
+            Role::System => system = Some(message.content.as_str()),
+            Role::User => {
+                let content = match system.take() {
+                    Some(system) => format!("{system}\n\n{}", message.content),
+                    None => message.content.clone(),
+                };
+                prompt.push_str(&format!("<start_of_turn>user\n{content}<end_of_turn>\n"));
+            }
+            Role::Assistant => prompt.push_str(&format!(
+                "<start_of_turn>model\n{}<end_of_turn>\n",
+                message.content
+            )),
+        }
+    }
+    prompt.push_str("<start_of_turn>model\n");
+    prompt
+}
+
+fn render_jinja(source: &str, conversation: &Conversation, bos: &str, eos: &str) -> Result<String> {
+    let mut env = minijinja::Environment::new();
+    env.add_function(
+        "raise_exception",
+        |msg: String| -> Result<String, minijinja::Error> {
+            Err(minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                msg,
+            ))
+        },
+    );
+    let messages = conversation
+        .messages()
+        .iter()
+        .map(|m| serde_json::json!({ "role": m.role.as_str(), "content": m.content }))
+        .collect::<Vec<_>>();
+    let template = env.template_from_str(source).map_err(E::msg)?;
+    let prompt = template
+        .render(serde_json::json!({
+            "messages": messages,
+            "bos_token": bos,
+            "eos_token": eos,
+            "add_generation_prompt": true,
+        }))
+        .map_err(E::msg)?;
+    Ok(prompt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation() -> Conversation {
+        let mut conversation = Conversation::new();
+        conversation.set_system("Be brief.");
+        conversation.push_user("Hi");
+        conversation.push_assistant("Hello");
+        conversation.push_user("How are you?");
+        conversation
+    }
+
+    fn render(template: ChatTemplate, bos: &str, eos: &str) -> String {
+        template.render(&conversation(), bos, eos).unwrap()
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(
+            render(ChatTemplate::Plain, "<s>", "</s>"),
+            "<s>System: Be brief.\nUser: Hi\nAssistant: Hello\nUser: How are you?\nAssistant:"
+        );
+    }
+
+    #[test]
+    fn mistral_instruct() {
+        assert_eq!(
+            render(ChatTemplate::MistralInstruct, "<s>", "</s>"),
+            "<s>[INST] Be brief.\n\nHi [/INST] Hello</s>[INST] How are you? [/INST]"
+        );
+    }
+
+    #[test]
+    fn mistral_nemo() {
+        assert_eq!(
+            render(ChatTemplate::MistralNemo, "<s>", "</s>"),
+            "<s>[INST]Be brief.\n\nHi[/INST]Hello</s>[INST]How are you?[/INST]"
+        );
+    }
+
+    #[test]
+    fn system_prompt_folded_once() {
+        let mut conversation = Conversation::new();
+        conversation.push_user("Hi");
+        assert_eq!(
+            ChatTemplate::MistralInstruct
+                .render(&conversation, "<s>", "</s>")
+                .unwrap(),
+            "<s>[INST] Hi [/INST]"
+        );
+        // Only the first user turn gets the system prompt.
+        let prompt = render(ChatTemplate::MistralNemo, "", "");
+        assert_eq!(prompt.matches("Be brief.").count(), 1);
+    }
+
+    #[test]
+    fn chatml() {
+        assert_eq!(
+            render(ChatTemplate::ChatMl, "", "<|endoftext|>"),
+            "<|im_start|>system\nBe brief.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n\
+             <|im_start|>assistant\nHello<|im_end|>\n\
+             <|im_start|>user\nHow are you?<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+    }
+
+    #[test]
+    fn llama3() {
+        let prompt = render(ChatTemplate::Llama3, "<|begin_of_text|>", "<|eot_id|>");
+        assert!(prompt.starts_with(
+            "<|begin_of_text|><|start_header_id|>system<|end_header_id|>\n\nBe brief.<|eot_id|>"
+        ));
+        assert!(prompt
+            .ends_with("How are you?<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n"));
+    }
+
+    #[test]
+    fn gemma() {
+        assert_eq!(
+            render(ChatTemplate::Gemma, "<bos>", "<eos>"),
+            "<bos><start_of_turn>user\nBe brief.\n\nHi<end_of_turn>\n\
+             <start_of_turn>model\nHello<end_of_turn>\n\
+             <start_of_turn>user\nHow are you?<end_of_turn>\n\
+             <start_of_turn>model\n"
+        );
+    }
+
+    #[test]
+    fn jinja() {
+        let template = ChatTemplate::Jinja(
+            "{{ bos_token }}{% for m in messages %}{{ m.role }}: {{ m.content }}\n{% endfor %}\
+             {% if add_generation_prompt %}assistant:{% endif %}"
+                .to_string(),
+        );
+        assert_eq!(
+            render(template, "<s>", "</s>"),
+            "<s>system: Be brief.\nuser: Hi\nassistant: Hello\nuser: How are you?\nassistant:"
+        );
+    }
+}
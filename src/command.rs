@@ -0,0 +1,192 @@
+use anyhow::{Context, Result};
+
+pub const HELP: &str = "\
+/reset                 forget the conversation, keeping the system prompt
+/system <text>         set the system prompt, empty to remove it
+/set temperature <f>   sampling temperature, `none` for greedy decoding
+/set top_p <f>         nucleus sampling probability cutoff, `none` to disable
+/set top_k <n>         only sample among the top K tokens, `none` to disable
+/set min_p <f>         min-p sampling cutoff, `none` to disable
+/set typical_p <f>     locally typical sampling mass, `none` to disable
+/set tfs_z <f>         tail-free sampling cutoff, `none` to disable
+/set mirostat_tau <f>  Mirostat v2 target surprise, `none` to disable
+/set mirostat_eta <f>  Mirostat v2 learning rate
+/set repeat_penalty <f>
+/set repeat_last_n <n>
+/set frequency_penalty <f>
+/set presence_penalty <f>
+/set sample_len <n>    maximum number of tokens per answer
+/seed <n>              re-seed the sampler
+/history               print the conversation so far
+/save [path]           save the session, by default to the --session file
+/quit                  exit
+/help                  print this message";
+
+/// A generation setting that can be changed between turns.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Setting {
+    Temperature(Option<f64>),
+    TopP(Option<f64>),
+    TopK(Option<usize>),
+    MinP(Option<f64>),
+    TypicalP(Option<f64>),
+    TfsZ(Option<f64>),
+    MirostatTau(Option<f64>),
+    MirostatEta(f64),
+    RepeatPenalty(f32),
+    RepeatLastN(usize),
+    FrequencyPenalty(f32),
+    PresencePenalty(f32),
+    SampleLen(usize),
+}
+
+/// A slash command typed at the prompt instead of a chat message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Reset,
+    System(String),
+    Set(Setting),
+    Seed(u64),
+    History,
+    Save(Option<String>),
+    Quit,
+    Help,
+}
+
+impl Command {
+    /// Parses a line of input, returns `None` when it is a regular chat
+    /// message rather than a slash command.
+    pub fn parse(line: &str) -> Option<Result<Self>> {
+        let line = line.trim();
+        let line = line.strip_prefix('/')?;
+        let (name, rest) = match line.split_once(char::is_whitespace) {
+            Some((name, rest)) => (name, rest.trim()),
+            None => (line, ""),
+        };
+        let command = match name {
+            "reset" => Ok(Self::Reset),
+            "system" => Ok(Self::System(rest.to_string())),
+            "set" => parse_setting(rest).map(Self::Set),
+            "seed" => rest
+                .parse()
+                .with_context(|| format!("invalid seed '{rest}'"))
+                .map(Self::Seed),
+            "history" => Ok(Self::History),
+            "save" if rest.is_empty() => Ok(Self::Save(None)),
+            "save" => Ok(Self::Save(Some(rest.to_string()))),
+            "quit" | "exit" => Ok(Self::Quit),
+            "help" => Ok(Self::Help),
+            _ => Err(anyhow::anyhow!("unknown command '/{name}', try /help")),
+        };
+        Some(command)
+    }
+}
+
+fn parse_setting(s: &str) -> Result<Setting> {
+    let (key, value) = match s.split_once(char::is_whitespace) {
+        Some((key, value)) => (key, value.trim()),
+        None => anyhow::bail!("usage: /set <name> <value>"),
+    };
+    let setting = match key {
+        "temperature" => Setting::Temperature(parse_optional(value)?),
+        "top_p" => Setting::TopP(parse_optional(value)?),
+        "top_k" => Setting::TopK(parse_optional(value)?),
+        "min_p" => Setting::MinP(parse_optional(value)?),
+        "typical_p" => Setting::TypicalP(parse_optional(value)?),
+        "tfs_z" => Setting::TfsZ(parse_optional(value)?),
+        "mirostat_tau" => Setting::MirostatTau(parse_optional(value)?),
+        "mirostat_eta" => Setting::MirostatEta(parse_value(value)?),
+        "repeat_penalty" => Setting::RepeatPenalty(parse_value(value)?),
+        "repeat_last_n" => Setting::RepeatLastN(parse_value(value)?),
+        "frequency_penalty" => Setting::FrequencyPenalty(parse_value(value)?),
+        "presence_penalty" => Setting::PresencePenalty(parse_value(value)?),
+        "sample_len" => Setting::SampleLen(parse_value(value)?),
+        _ => anyhow::bail!("unknown setting '{key}', try /help"),
+    };
+    Ok(setting)
+}
+
+fn parse_value<T>(value: &str) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    value
+        .parse()
+        .with_context(|| format!("invalid value '{value}'"))
+}
+
+fn parse_optional<T>(value: &str) -> Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match value {
+        "none" | "off" => Ok(None),
+        value => parse_value(value).map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Command {
+        Command::parse(line).unwrap().unwrap()
+    }
+
+    #[test]
+    fn chat_message() {
+        assert!(Command::parse("Hello /reset").is_none());
+        assert!(Command::parse("").is_none());
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse("/reset"), Command::Reset);
+        assert_eq!(parse("  /quit  "), Command::Quit);
+        assert_eq!(parse("/exit"), Command::Quit);
+        assert_eq!(parse("/seed 42"), Command::Seed(42));
+        assert_eq!(
+            parse("/system  Be brief. "),
+            Command::System("Be brief.".into())
+        );
+        assert_eq!(parse("/system"), Command::System(String::new()));
+        assert_eq!(parse("/save"), Command::Save(None));
+        assert_eq!(
+            parse("/save a b.json"),
+            Command::Save(Some("a b.json".into()))
+        );
+    }
+
+    #[test]
+    fn settings() {
+        assert_eq!(
+            parse("/set temperature 0.7"),
+            Command::Set(Setting::Temperature(Some(0.7)))
+        );
+        assert_eq!(parse("/set top_k none"), Command::Set(Setting::TopK(None)));
+        assert_eq!(parse("/set top_p off"), Command::Set(Setting::TopP(None)));
+        assert_eq!(
+            parse("/set repeat_last_n 32"),
+            Command::Set(Setting::RepeatLastN(32))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        for line in [
+            "/frobnicate",
+            "/seed",
+            "/seed -1",
+            "/set",
+            "/set temperature",
+            "/set temperature hot",
+            "/set top_k 0.5",
+            "/set mirostat_eta none",
+            "/set unknown 1",
+        ] {
+            assert!(Command::parse(line).unwrap().is_err(), "{line}");
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use crate::Args;
+
+/// Prefix of the environment variables setting the flags, e.g.
+/// `RCHATBOT_SAMPLE_LEN` for `--sample-len`.
+const ENV_PREFIX: &str = "RCHATBOT_";
+
+/// A value of the config file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum Value {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    List(Vec<Value>),
+}
+
+impl Value {
+    fn to_strings(&self) -> Vec<String> {
+        match self {
+            Value::Bool(b) => vec![b.to_string()],
+            Value::Integer(i) => vec![i.to_string()],
+            Value::Float(f) => vec![f.to_string()],
+            Value::String(s) => vec![s.clone()],
+            Value::List(values) => values.iter().flat_map(|v| v.to_strings()).collect(),
+        }
+    }
+}
+
+/// The command line arguments, layered over the `RCHATBOT_*` environment
+/// variables, over the config file, over the built-in defaults.
+pub struct Config {
+    pub args: Args,
+    matches: ArgMatches,
+    /// The config file, `$RCHATBOT_CONFIG`, which must exist, or else
+    /// `config.toml` in the user config directory, when it exists.
+    file: Option<PathBuf>,
+    /// Ids of the arguments whose default comes from the config file.
+    from_file: BTreeSet<String>,
+}
+
+/// The config file to read. A missing file is only skipped for the default
+/// path, a mistyped `$RCHATBOT_CONFIG` is an error when reading it.
+fn config_file() -> Option<PathBuf> {
+    match std::env::var_os(format!("{ENV_PREFIX}CONFIG")) {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(chatbot::registry::config_dir()?.join("config.toml")).filter(|p| p.exists()),
+    }
+}
+
+fn env_var(id: &str) -> String {
+    format!("{ENV_PREFIX}{}", id.to_uppercase())
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        let mut command = Args::command().mut_args(|arg| {
+            let env = env_var(arg.get_id().as_str());
+            arg.env(env)
+        });
+
+        // The config file values replace the built-in defaults, so that the
+        // environment and the command line still override them.
+        let file = config_file();
+        let mut from_file = BTreeSet::new();
+        if let Some(path) = &file {
+            let data = std::fs::read_to_string(path)
+                .with_context(|| format!("cannot read config file {}", path.display()))?;
+            let values: BTreeMap<String, Value> = toml::from_str(&data)
+                .with_context(|| format!("invalid config file {}", path.display()))?;
+            for (key, value) in values {
+                let id = key.replace('-', "_");
+                if id == "action"
+                    || !command
+                        .get_arguments()
+                        .any(|arg| arg.get_id() == id.as_str())
+                {
+                    anyhow::bail!("unknown option '{key}' in config file {}", path.display())
+                }
+                command = command.mut_arg(&id, |arg| arg.default_values(value.to_strings()));
+                from_file.insert(id);
+            }
+        }
+
+        let matches = command.get_matches();
+        let args = Args::from_arg_matches(&matches)?;
+        Ok(Self {
+            args,
+            matches,
+            file,
+            from_file,
+        })
+    }
+
+    /// Ids of the arguments given on the command line or through the
+    /// environment, which nothing else overrides.
+    pub fn explicit_args(&self) -> BTreeSet<String> {
+        Args::command()
+            .get_arguments()
+            .map(|arg| arg.get_id().as_str())
+            .filter(|id| {
+                matches!(
+                    self.matches.value_source(id),
+                    Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+                )
+            })
+            .map(|id| id.to_string())
+            .collect()
+    }
+
+    /// Prints every option with its effective value and where it comes from.
+    pub fn show(&self) {
+        match &self.file {
+            Some(path) => println!("config file: {}", path.display()),
+            None => println!("config file: none"),
+        }
+        let command = Args::command();
+        let ids = command
+            .get_arguments()
+            .map(|arg| arg.get_id().as_str())
+            .filter(|id| !matches!(*id, "help" | "version"));
+        for id in ids {
+            let value = match self.matches.get_raw(id) {
+                Some(values) => values
+                    .map(|v| format!("{:?}", v.to_string_lossy()))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                None => "-".to_string(),
+            };
+            let source = match self.matches.value_source(id) {
+                Some(ValueSource::CommandLine) => "command line".to_string(),
+                Some(ValueSource::EnvVariable) => format!("env {}", env_var(id)),
+                Some(_) if self.from_file.contains(id) => "config file".to_string(),
+                Some(_) => "default".to_string(),
+                None => "unset".to_string(),
+            };
+            println!("{id:<24}{value:<32}{source}");
+        }
+    }
+}
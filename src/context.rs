@@ -0,0 +1,144 @@
+use anyhow::Result;
+
+use crate::conversation::Conversation;
+
+/// Keeps the prompt plus the generated tokens within the model context window.
+#[derive(Clone, Debug, Copy)]
+pub struct ContextBudget {
+    max_tokens: usize,
+}
+
+/// What had to be done to fit a conversation in the context window.
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+pub struct Fit {
+    /// Number of tokens in the prompt after truncation.
+    pub prompt_tokens: usize,
+    /// Number of tokens left for generation, at most the requested sample length.
+    pub sample_len: usize,
+    /// Number of old messages left out of the prompt.
+    pub dropped: usize,
+}
+
+impl ContextBudget {
+    pub fn new(max_tokens: usize) -> Self {
+        Self { max_tokens }
+    }
+
+    pub fn max_tokens(&self) -> usize {
+        self.max_tokens
+    }
+
+    /// Drops the oldest turns of `conversation`, never the system prompt nor
+    /// the latest message, until the prompt plus `sample_len` tokens fit in the
+    /// window. `count_tokens` returns the prompt length of a conversation.
+    ///
+    /// When only the system prompt and the latest message are left, the sample
+    /// length is reduced instead, and an error is returned if even the prompt
+    /// alone does not fit.
+    pub fn fit<F>(
+        &self,
+        conversation: &mut Conversation,
+        sample_len: usize,
+        count_tokens: F,
+    ) -> Result<Fit>
+    where
+        F: Fn(&Conversation) -> Result<usize>,
+    {
+        let mut dropped = 0;
+        let mut prompt_tokens = count_tokens(conversation)?;
+        while prompt_tokens + sample_len > self.max_tokens {
+            let n = conversation.drop_oldest_turn();
+            if n == 0 {
+                break;
+            }
+            dropped += n;
+            prompt_tokens = count_tokens(conversation)?;
+        }
+        if prompt_tokens >= self.max_tokens {
+            anyhow::bail!(
+                "the prompt is {prompt_tokens} tokens long, the context window is {} tokens",
+                self.max_tokens
+            )
+        }
+        Ok(Fit {
+            prompt_tokens,
+            sample_len: sample_len.min(self.max_tokens - prompt_tokens),
+            dropped,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::Role;
+
+    /// Six messages after the system prompt, ten tokens each.
+    fn conversation() -> Conversation {
+        let mut conversation = Conversation::new();
+        conversation.set_system("Be brief.");
+        for turn in 0..3 {
+            conversation.push_user(&format!("Question {turn}"));
+            conversation.push_assistant(&format!("Answer {turn}"));
+        }
+        conversation.push_user("Last question");
+        conversation
+    }
+
+    fn count_tokens(conversation: &Conversation) -> Result<usize> {
+        Ok(conversation.messages().len() * 10)
+    }
+
+    #[test]
+    fn fits_as_is() {
+        let mut conversation = conversation();
+        let fit = ContextBudget::new(100)
+            .fit(&mut conversation, 20, count_tokens)
+            .unwrap();
+        let expected = Fit {
+            prompt_tokens: 80,
+            sample_len: 20,
+            dropped: 0,
+        };
+        assert_eq!(fit, expected);
+        assert_eq!(conversation.messages().len(), 8);
+    }
+
+    #[test]
+    fn drops_the_oldest_turns() {
+        let mut conversation = conversation();
+        let fit = ContextBudget::new(60)
+            .fit(&mut conversation, 20, count_tokens)
+            .unwrap();
+        let expected = Fit {
+            prompt_tokens: 40,
+            sample_len: 20,
+            dropped: 4,
+        };
+        assert_eq!(fit, expected);
+        assert_eq!(conversation.messages()[0].role, Role::System);
+        assert_eq!(conversation.messages()[1].content, "Question 2");
+    }
+
+    #[test]
+    fn reduces_the_sample_len() {
+        let mut conversation = conversation();
+        let fit = ContextBudget::new(50)
+            .fit(&mut conversation, 100, count_tokens)
+            .unwrap();
+        let expected = Fit {
+            prompt_tokens: 20,
+            sample_len: 30,
+            dropped: 6,
+        };
+        assert_eq!(fit, expected);
+        assert_eq!(conversation.messages()[1].content, "Last question");
+    }
+
+    #[test]
+    fn prompt_too_long() {
+        let mut conversation = conversation();
+        let budget = ContextBudget::new(20);
+        assert!(budget.fit(&mut conversation, 10, count_tokens).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use serde::{Deserialize, Serialize};
+
+/// Who is speaking in a conversation turn.
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        }
+    }
+}
+
+/// A single turn in the dialogue.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+}
+
+/// The accumulated dialogue between the user and the bot.
+#[derive(Clone, Debug, Default)]
+pub struct Conversation {
+    messages: Vec<Message>,
+}
+
+impl Conversation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    pub fn push(&mut self, role: Role, content: &str) {
+        self.messages.push(Message {
+            role,
+            content: content.trim().to_string(),
+        });
+    }
+
+    pub fn push_user(&mut self, content: &str) {
+        self.push(Role::User, content)
+    }
+
+    pub fn push_assistant(&mut self, content: &str) {
+        self.push(Role::Assistant, content)
+    }
+
+    /// Sets the system prompt, which always comes first in the dialogue. An
+    /// empty prompt removes it.
+    pub fn set_system(&mut self, content: &str) {
+        self.messages.retain(|m| m.role != Role::System);
+        if !content.trim().is_empty() {
+            self.messages.insert(
+                0,
+                Message {
+                    role: Role::System,
+                    content: content.trim().to_string(),
+                },
+            );
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Message> {
+        self.messages.pop()
+    }
+
+    /// Removes the oldest user turn and the assistant answer that follows
+    /// it. The system prompt and the latest message are always kept, returns
+    /// the number of messages removed.
+    pub fn drop_oldest_turn(&mut self) -> usize {
+        let last = self.messages.len().saturating_sub(1);
+        let first = match self.messages[..last]
+            .iter()
+            .position(|m| m.role != Role::System)
+        {
+            Some(first) => first,
+            None => return 0,
+        };
+        let mut end = first + 1;
+        while end < last && self.messages[end].role == Role::Assistant {
+            end += 1;
+        }
+        self.messages.drain(first..end);
+        end - first
+    }
+
+    /// Forgets the dialogue, keeping the system prompt.
+    pub fn reset(&mut self) {
+        self.messages.retain(|m| m.role == Role::System);
+    }
+
+    /// Renders the whole dialogue as a plain transcript, ending with an open
+    /// assistant turn for the model to complete.
+    pub fn prompt(&self) -> String {
+        let mut prompt = String::new();
+        for message in self.messages.iter() {
+            let speaker = match message.role {
+                Role::System => "System",
+                Role::User => "User",
+                Role::Assistant => "Assistant",
+            };
+            prompt.push_str(&format!("{speaker}: {}\n", message.content));
+        }
+        prompt.push_str("Assistant:");
+        prompt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roles(conversation: &Conversation) -> Vec<Role> {
+        conversation.messages().iter().map(|m| m.role).collect()
+    }
+
+    #[test]
+    fn drop_oldest_turn() {
+        let mut conversation = Conversation::new();
+        conversation.set_system("Be brief.");
+        conversation.push_user("Hi");
+        conversation.push_assistant("Hello");
+        conversation.push_user("How are you?");
+        assert_eq!(conversation.drop_oldest_turn(), 2);
+        assert_eq!(roles(&conversation), [Role::System, Role::User]);
+        assert_eq!(conversation.messages()[1].content, "How are you?");
+        // The system prompt and the latest message stay.
+        assert_eq!(conversation.drop_oldest_turn(), 0);
+        assert_eq!(conversation.messages().len(), 2);
+    }
+
+    #[test]
+    fn drop_unanswered_turn() {
+        let mut conversation = Conversation::new();
+        conversation.push_user("Hi");
+        conversation.push_user("Hello?");
+        assert_eq!(conversation.drop_oldest_turn(), 1);
+        assert_eq!(conversation.messages()[0].content, "Hello?");
+        assert_eq!(Conversation::new().drop_oldest_turn(), 0);
+    }
+
+    #[test]
+    fn set_system_and_reset() {
+        let mut conversation = Conversation::new();
+        conversation.push_user("Hi");
+        conversation.set_system("Be brief.");
+        conversation.set_system(" Be nice. ");
+        assert_eq!(roles(&conversation), [Role::System, Role::User]);
+        assert_eq!(conversation.messages()[0].content, "Be nice.");
+        conversation.reset();
+        assert_eq!(roles(&conversation), [Role::System]);
+        conversation.set_system("");
+        assert!(conversation.messages().is_empty());
+    }
+}
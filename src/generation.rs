@@ -0,0 +1,609 @@
+use anyhow::{Error as E, Result};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use tokenizers::Tokenizer;
+
+use candle_core::{DType, Device, Tensor, D};
+use candle_examples::token_output_stream::TokenOutputStream;
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+
+use crate::cancel::{ActiveGeneration, CancelToken};
+use crate::chat_model::{ChatModel, ModelConfig};
+use crate::chat_template::ChatTemplate;
+use crate::context::{ContextBudget, Fit};
+use crate::conversation::Conversation;
+use crate::logit_bias::LogitBias;
+use crate::logits::{FrequencyPenalty, LogitsContext, LogitsPipeline, RepeatPenalty, TokenMask};
+use crate::sampling::SamplingConfig;
+use crate::stop::StopSequences;
+
+/// Draws from the distribution left by the logits pipeline, which already
+/// applied the temperature.
+fn logits_processor(seed: u64) -> LogitsProcessor {
+    LogitsProcessor::from_sampling(seed, Sampling::All { temperature: 1. })
+}
+
+pub struct TextGeneration<'a, 'b> {
+    model: &'a mut dyn ChatModel,
+    device: &'b Device,
+    tokenizer: TokenOutputStream,
+    /// Transforms the logits of every step before sampling.
+    logits_pipeline: LogitsPipeline,
+    sampling: SamplingConfig,
+    /// Draws the token from the transformed logits, unless greedy.
+    logits_processor: LogitsProcessor,
+    /// Tokens whose keys and values are currently held in the model cache.
+    cached_tokens: Vec<u32>,
+    cancel: CancelToken,
+    stop: StopSequences,
+}
+
+impl<'a, 'b, 'c> TextGeneration<'a, 'b> {
+    pub fn new(
+        model: &'a mut dyn ChatModel,
+        tokenizer: &'c Tokenizer,
+        seed: u64,
+        sampling: SamplingConfig,
+        repeat_penalty: f32,
+        repeat_last_n: usize,
+        device: &'b Device,
+    ) -> Self {
+        // The default stages, in order: the penalties, the biases, the token
+        // mask and the sampling filters, which only see the allowed tokens.
+        let mut logits_pipeline = LogitsPipeline::new();
+        logits_pipeline.push(Box::new(RepeatPenalty {
+            penalty: repeat_penalty,
+            last_n: repeat_last_n,
+        }));
+        logits_pipeline.push(Box::new(FrequencyPenalty {
+            frequency: 0.,
+            presence: 0.,
+        }));
+        logits_pipeline.push(Box::new(LogitBias::default()));
+        logits_pipeline.push(Box::new(TokenMask::default()));
+        for transform in sampling.transforms() {
+            logits_pipeline.push(transform);
+        }
+
+        TextGeneration {
+            model,
+            tokenizer: TokenOutputStream::new(tokenizer.clone()),
+            logits_pipeline,
+            sampling,
+            logits_processor: logits_processor(seed),
+            device,
+            cached_tokens: vec![],
+            cancel: CancelToken::new(),
+            stop: StopSequences::default(),
+        }
+    }
+
+    pub fn tokenizer(&self) -> &Tokenizer {
+        self.tokenizer.tokenizer()
+    }
+
+    pub fn model_config(&self) -> &ModelConfig {
+        self.model.config()
+    }
+
+    pub fn set_stop_sequences(&mut self, stop: StopSequences) {
+        self.stop = stop;
+    }
+
+    /// Returns a token that stops the generation in progress when cancelled.
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// The stages transforming the logits before sampling, to add, remove
+    /// or reorder them.
+    pub fn logits_pipeline_mut(&mut self) -> &mut LogitsPipeline {
+        &mut self.logits_pipeline
+    }
+
+    /// Replaces the sampling stages when the settings changed. The random
+    /// generator carries on, see `set_seed`.
+    pub fn set_sampling(&mut self, sampling: SamplingConfig) {
+        if sampling == self.sampling {
+            return;
+        }
+        for transform in sampling.transforms() {
+            self.logits_pipeline.replace(transform);
+        }
+        self.sampling = sampling;
+    }
+
+    /// Restarts the random generator of the sampler from `seed`.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.logits_processor = logits_processor(seed);
+    }
+
+    pub fn set_repeat_penalty(&mut self, repeat_penalty: f32, repeat_last_n: usize) {
+        self.logits_pipeline.replace(Box::new(RepeatPenalty {
+            penalty: repeat_penalty,
+            last_n: repeat_last_n,
+        }));
+    }
+
+    /// Sets the OpenAI style penalties, subtracted from the logits of the
+    /// tokens already generated: `frequency_penalty` for every occurrence and
+    /// `presence_penalty` once. 0. disables them.
+    pub fn set_frequency_penalty(&mut self, frequency_penalty: f32, presence_penalty: f32) {
+        self.logits_pipeline.replace(Box::new(FrequencyPenalty {
+            frequency: frequency_penalty,
+            presence: presence_penalty,
+        }));
+    }
+
+    /// Sets the biases added to the logits before sampling.
+    pub fn set_logit_bias(&mut self, logit_bias: LogitBias) {
+        self.logits_pipeline.replace(Box::new(logit_bias));
+    }
+
+    /// Only lets `allowed` tokens be generated, e.g. the ones a grammar
+    /// accepts next, which should include the end of sequence token. `None`
+    /// allows every token.
+    pub fn set_allowed_tokens(&mut self, allowed: Option<HashSet<u32>>) {
+        self.logits_pipeline
+            .replace(Box::new(TokenMask { allowed }));
+    }
+
+    /// Drops the model cache, the next run starts again from position 0.
+    pub fn reset(&mut self) {
+        self.model.clear_kv_cache();
+        self.cached_tokens.clear();
+    }
+
+    /// Starts generating a reply to `prompt`, the returned stream yields the
+    /// tokens as they are produced.
+    ///
+    /// The prompt is expected to hold the whole dialogue so far, already
+    /// rendered by a chat template including the BOS token. When it extends
+    /// the tokens held in the model cache only the new tokens are processed,
+    /// otherwise the cache is cleared and the prompt is processed from
+    /// position 0.
+    ///
+    /// Cancelling the token returned by `cancel_token` ends the stream early.
+    /// Stop sequences are trimmed from the output.
+    pub fn stream<'g>(
+        &'g mut self,
+        prompt: &str,
+        sample_len: usize,
+    ) -> Result<TokenStream<'g, 'a, 'b>> {
+        self.tokenizer.clear();
+        let tokens = {
+            let _span = tracing::trace_span!("tokenize").entered();
+            self.tokenizer
+                .tokenizer()
+                .encode(prompt, false)
+                .map_err(E::msg)?
+                .get_ids()
+                .to_vec()
+        };
+        if tokens.is_empty() {
+            anyhow::bail!("the prompt is empty")
+        }
+        let eos_tokens = self.model.config().eos_token_ids.clone();
+
+        // The cached tokens are taken out so that an error half-way through
+        // leaves an empty list and forces a cache reset on the next run.
+        let cached_tokens = std::mem::take(&mut self.cached_tokens);
+        let processed = if !cached_tokens.is_empty()
+            && tokens.len() > cached_tokens.len()
+            && tokens.starts_with(&cached_tokens)
+        {
+            cached_tokens.len()
+        } else {
+            self.model.clear_kv_cache();
+            0
+        };
+
+        let active = self.cancel.start();
+        Ok(TokenStream {
+            generation: self,
+            prompt_tokens: tokens.len(),
+            tokens,
+            processed,
+            eos_tokens,
+            sample_len,
+            generated_tokens: 0,
+            answer: String::new(),
+            emitted: 0,
+            finish_reason: None,
+            failed: false,
+            cached_prompt_tokens: processed,
+            start: Instant::now(),
+            time_to_first_token: None,
+            _active: active,
+        })
+    }
+
+    /// Generates a reply to `prompt`, handing every token to `on_token` as it
+    /// is produced. See `stream`.
+    pub fn generate<F>(
+        &mut self,
+        prompt: &str,
+        sample_len: usize,
+        mut on_token: F,
+    ) -> Result<GenerationResult>
+    where
+        F: FnMut(&Token) -> Result<()>,
+    {
+        let mut stream = self.stream(prompt, sample_len)?;
+        for token in &mut stream {
+            on_token(&token?)?;
+        }
+        Ok(stream.result())
+    }
+}
+
+/// Why the generation ended.
+#[derive(Clone, Debug, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinishReason {
+    /// The model produced the end of sequence token.
+    Eos,
+    /// A stop sequence or stop token was produced.
+    Stop,
+    /// The maximum number of tokens was generated.
+    Length,
+    /// The generation was cancelled through the cancel token.
+    Cancelled,
+}
+
+/// A token produced by the model.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub id: u32,
+    /// The text that can be shown for this token. It can be empty, e.g. when
+    /// the token is only part of a character or might start a stop sequence,
+    /// the text then comes with a later token.
+    pub text: String,
+    /// Log probability of the token under the distribution it was sampled
+    /// from, after the logits pipeline.
+    pub logprob: f32,
+    /// Set on the last token of the generation.
+    pub finish_reason: Option<FinishReason>,
+}
+
+fn as_secs<S: serde::Serializer>(d: &Duration, s: S) -> std::result::Result<S::Ok, S::Error> {
+    s.serialize_f64(d.as_secs_f64())
+}
+
+/// Timings and token counts of a generation, durations are serialized in
+/// seconds.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct GenerationStats {
+    pub prompt_tokens: usize,
+    /// Prompt tokens fed to the model, the others were already in the cache.
+    pub prompt_tokens_processed: usize,
+    pub generated_tokens: usize,
+    /// From the start of the generation to the first sampled token, that is
+    /// the prompt processing time.
+    #[serde(serialize_with = "as_secs")]
+    pub time_to_first_token: Duration,
+    /// Prompt processing throughput, in tokens per second.
+    pub prompt_tokens_per_second: f64,
+    /// Throughput of the tokens generated after the first one, in tokens per
+    /// second.
+    pub decode_tokens_per_second: f64,
+    #[serde(serialize_with = "as_secs")]
+    pub total_latency: Duration,
+    pub finish_reason: FinishReason,
+}
+
+impl std::fmt::Display for GenerationStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} tokens generated ({:.2} token/s), prompt {} tokens with {} processed \
+             ({:.2} token/s), first token after {:.2?}, total {:.2?}, finish reason {:?}",
+            self.generated_tokens,
+            self.decode_tokens_per_second,
+            self.prompt_tokens,
+            self.prompt_tokens_processed,
+            self.prompt_tokens_per_second,
+            self.time_to_first_token,
+            self.total_latency,
+            self.finish_reason,
+        )
+    }
+}
+
+/// The outcome of a whole generation.
+#[derive(Clone, Debug)]
+pub struct GenerationResult {
+    /// The generated text, without any stop sequence.
+    pub text: String,
+    pub stats: GenerationStats,
+}
+
+/// Iterator over the tokens generated for a prompt, one decode step at a time.
+pub struct TokenStream<'g, 'a, 'b> {
+    generation: &'g mut TextGeneration<'a, 'b>,
+    tokens: Vec<u32>,
+    prompt_tokens: usize,
+    /// Number of tokens already fed to the model.
+    processed: usize,
+    eos_tokens: Vec<u32>,
+    sample_len: usize,
+    generated_tokens: usize,
+    answer: String,
+    /// Length of the answer already yielded, the rest might be the start of a
+    /// stop sequence.
+    emitted: usize,
+    finish_reason: Option<FinishReason>,
+    failed: bool,
+    /// Prompt tokens already held in the model cache.
+    cached_prompt_tokens: usize,
+    start: Instant,
+    time_to_first_token: Option<Duration>,
+    _active: ActiveGeneration,
+}
+
+impl TokenStream<'_, '_, '_> {
+    /// The text generated so far, without any stop sequence.
+    pub fn text(&self) -> &str {
+        &self.answer
+    }
+
+    /// Why the generation ended, `None` while it is still running.
+    pub fn finish_reason(&self) -> Option<FinishReason> {
+        self.finish_reason
+    }
+
+    /// Consumes the remaining tokens and returns the outcome.
+    pub fn finish(mut self) -> Result<GenerationResult> {
+        for token in &mut self {
+            token?;
+        }
+        Ok(self.result())
+    }
+
+    /// The outcome of the generation so far.
+    pub fn result(&self) -> GenerationResult {
+        GenerationResult {
+            text: self.answer.clone(),
+            stats: self.stats(),
+        }
+    }
+
+    pub fn stats(&self) -> GenerationStats {
+        let total_latency = self.start.elapsed();
+        let time_to_first_token = self.time_to_first_token.unwrap_or(total_latency);
+        let per_second = |tokens: usize, d: Duration| {
+            if tokens == 0 || d.is_zero() {
+                0.
+            } else {
+                tokens as f64 / d.as_secs_f64()
+            }
+        };
+        let prompt_tokens_processed = self.prompt_tokens - self.cached_prompt_tokens;
+        GenerationStats {
+            prompt_tokens: self.prompt_tokens,
+            prompt_tokens_processed,
+            generated_tokens: self.generated_tokens,
+            time_to_first_token,
+            prompt_tokens_per_second: per_second(prompt_tokens_processed, time_to_first_token),
+            decode_tokens_per_second: per_second(
+                self.generated_tokens.saturating_sub(1),
+                total_latency.saturating_sub(time_to_first_token),
+            ),
+            total_latency,
+            finish_reason: self.finish_reason.unwrap_or(FinishReason::Length),
+        }
+    }
+
+    /// Runs one decode step.
+    fn step(&mut self) -> Result<Token> {
+        let start_pos = self.processed;
+        let ctxt = &self.tokens[start_pos..];
+        // The first step processes the prompt, the next ones a single token.
+        let span = if self.generated_tokens == 0 {
+            tracing::trace_span!("prefill", tokens = ctxt.len(), start_pos)
+        } else {
+            tracing::trace_span!("decode", start_pos)
+        };
+        let logits = span.in_scope(|| -> Result<Tensor> {
+            let input = Tensor::new(ctxt, self.generation.device)?.unsqueeze(0)?;
+            let logits = self.generation.model.forward(&input, start_pos)?;
+            Ok(logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?)
+        })?;
+        self.processed = self.tokens.len();
+
+        let sample_span = tracing::trace_span!("sample").entered();
+        let context = LogitsContext {
+            tokens: &self.tokens,
+            prompt_tokens: self.prompt_tokens,
+        };
+        let logits = self.generation.logits_pipeline.apply(&logits, &context)?;
+        let next_token = if self.generation.sampling.is_greedy() {
+            logits.argmax(D::Minus1)?.to_scalar::<u32>()?
+        } else {
+            self.generation.logits_processor.sample(&logits)?
+        };
+        self.generation.logits_pipeline.accept(next_token);
+        let logprob = candle_nn::ops::log_softmax(&logits, D::Minus1)?
+            .get(next_token as usize)?
+            .to_scalar::<f32>()?;
+        drop(sample_span);
+        self.tokens.push(next_token);
+        self.generated_tokens += 1;
+        if self.time_to_first_token.is_none() {
+            self.time_to_first_token = Some(self.start.elapsed());
+        }
+
+        let mut text = String::new();
+        // Whatever the tokenizer still holds comes after a stop sequence.
+        let mut decode_rest = true;
+        if self.eos_tokens.contains(&next_token) {
+            self.finish_reason = Some(FinishReason::Eos);
+        } else if self.generation.stop.is_stop_token(next_token) {
+            self.finish_reason = Some(FinishReason::Stop);
+        } else if let Some(t) = self.generation.tokenizer.next_token(next_token)? {
+            text = self.push_text(&t);
+            decode_rest = self.finish_reason.is_none();
+        }
+        if self.finish_reason.is_none() {
+            if self.generated_tokens >= self.sample_len {
+                self.finish_reason = Some(FinishReason::Length);
+            } else if self.generation.cancel.is_cancelled() {
+                self.finish_reason = Some(FinishReason::Cancelled);
+            }
+        }
+        if self.finish_reason.is_some() {
+            text.push_str(&self.flush(decode_rest)?);
+        }
+        Ok(Token {
+            id: next_token,
+            text,
+            logprob,
+            finish_reason: self.finish_reason,
+        })
+    }
+
+    /// Returns the text held back by the stop sequences, and by the tokenizer
+    /// when `decode_rest` is set, once the generation is over.
+    fn flush(&mut self, decode_rest: bool) -> Result<String> {
+        if decode_rest {
+            if let Some(rest) = self.generation.tokenizer.decode_rest().map_err(E::msg)? {
+                // The tokenizer holds back the trailing punctuation, e.g. the
+                // `:` completing `\nUser:`.
+                let stop = &self.generation.stop;
+                self.answer.push_str(&rest);
+                if stop.truncate(&mut self.answer, self.emitted) {
+                    self.finish_reason = Some(FinishReason::Stop);
+                }
+            }
+        }
+        let text = self.answer[self.emitted..].to_string();
+        self.emitted = self.answer.len();
+        Ok(text)
+    }
+
+    /// Appends `text` to the answer and returns the part of the answer that
+    /// cannot be the start of a stop sequence. When a stop sequence is found
+    /// the answer is truncated just before it and the generation ends.
+    fn push_text(&mut self, text: &str) -> String {
+        let stop = &self.generation.stop;
+        self.answer.push_str(text);
+        let end = if stop.truncate(&mut self.answer, self.emitted) {
+            self.finish_reason = Some(FinishReason::Stop);
+            self.answer.len()
+        } else {
+            self.answer.len() - stop.partial_len(&self.answer[self.emitted..])
+        };
+        let text = self.answer[self.emitted..end].to_string();
+        self.emitted = end;
+        text
+    }
+}
+
+impl Iterator for TokenStream<'_, '_, '_> {
+    type Item = Result<Token>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finish_reason.is_some() || self.failed {
+            return None;
+        }
+        if self.sample_len == 0 {
+            self.finish_reason = Some(FinishReason::Length);
+            return None;
+        }
+        let token = self.step();
+        if token.is_err() {
+            self.failed = true;
+        }
+        Some(token)
+    }
+}
+
+impl Drop for TokenStream<'_, '_, '_> {
+    fn drop(&mut self) {
+        if !self.failed {
+            self.tokens.truncate(self.processed);
+            self.generation.cached_tokens = std::mem::take(&mut self.tokens);
+        }
+    }
+}
+
+/// A conversation with a model: keeps the dialogue, renders it with the chat
+/// template and fits it in the context window before every answer.
+pub struct ChatSession<'p, 'a, 'b> {
+    pub generation: &'p mut TextGeneration<'a, 'b>,
+    pub conversation: Conversation,
+    pub template: ChatTemplate,
+    pub budget: ContextBudget,
+    /// How the last prompt was fitted in the context window.
+    pub last_fit: Option<Fit>,
+}
+
+impl<'p, 'a, 'b> ChatSession<'p, 'a, 'b> {
+    pub fn new(
+        generation: &'p mut TextGeneration<'a, 'b>,
+        template: ChatTemplate,
+        budget: ContextBudget,
+    ) -> Self {
+        Self {
+            generation,
+            conversation: Conversation::new(),
+            template,
+            budget,
+            last_fit: None,
+        }
+    }
+
+    /// Forgets the dialogue, keeping the system prompt, and the model cache.
+    pub fn reset(&mut self) {
+        self.conversation.reset();
+        self.generation.reset();
+    }
+
+    /// Renders the conversation, leaving the oldest turns out of the prompt
+    /// when it does not fit in the context window. The conversation itself
+    /// keeps every turn. Returns the prompt and the fit details.
+    pub fn prompt(&mut self, sample_len: usize) -> Result<(String, Fit)> {
+        let tokenizer = self.generation.tokenizer();
+        let config = self.generation.model_config();
+        let (bos, eos) = (&config.bos_token, &config.eos_token);
+        let template = &self.template;
+        let mut window = self.conversation.clone();
+        let fit = self.budget.fit(&mut window, sample_len, |conversation| {
+            let prompt = template.render(conversation, bos, eos)?;
+            let _span = tracing::trace_span!("tokenize").entered();
+            let tokens = tokenizer.encode(prompt, false).map_err(E::msg)?;
+            Ok(tokens.len())
+        })?;
+        let prompt = self.template.render(&window, bos, eos)?;
+        self.last_fit = Some(fit);
+        Ok((prompt, fit))
+    }
+
+    /// Adds `message` as a user turn and generates the answer, handing every
+    /// token to `on_token`. The answer is appended to the conversation, on
+    /// failure the user turn is removed instead.
+    pub fn send_with<F>(
+        &mut self,
+        message: &str,
+        sample_len: usize,
+        on_token: F,
+    ) -> Result<GenerationResult>
+    where
+        F: FnMut(&Token) -> Result<()>,
+    {
+        self.conversation.push_user(message);
+        let result = self
+            .prompt(sample_len)
+            .and_then(|(prompt, fit)| self.generation.generate(&prompt, fit.sample_len, on_token));
+        match result {
+            Ok(result) => {
+                self.conversation.push_assistant(&result.text);
+                Ok(result)
+            }
+            Err(err) => {
+                self.conversation.pop();
+                Err(err)
+            }
+        }
+    }
+}
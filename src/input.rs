@@ -0,0 +1,56 @@
+use anyhow::Result;
+use std::io::{BufRead, IsTerminal, Write};
+
+/// Reads user turns from stdin, either typed at the prompt or piped in.
+pub struct Input {
+    stdin: std::io::StdinLock<'static>,
+    interactive: bool,
+    blocks: bool,
+}
+
+impl Input {
+    /// With `blocks` set, a turn is a block of lines ended by a blank line
+    /// rather than a single line.
+    pub fn new(blocks: bool) -> Self {
+        let stdin = std::io::stdin();
+        let interactive = stdin.is_terminal();
+        Self {
+            stdin: stdin.lock(),
+            interactive,
+            blocks,
+        }
+    }
+
+    /// Returns the next user turn, or `None` once the input is exhausted
+    /// (end of the piped input or Ctrl-D). Blank turns are skipped.
+    pub fn read_turn(&mut self) -> Result<Option<String>> {
+        let mut turn = String::new();
+        loop {
+            if self.interactive && turn.is_empty() {
+                print!("> ");
+                std::io::stdout().flush()?;
+            }
+            let mut line = String::new();
+            if self.stdin.read_line(&mut line)? == 0 {
+                break;
+            }
+            if line.trim().is_empty() {
+                if turn.is_empty() {
+                    continue;
+                }
+                break;
+            }
+            turn.push_str(&line);
+            if !self.blocks {
+                break;
+            }
+        }
+        if turn.is_empty() {
+            if self.interactive {
+                println!();
+            }
+            return Ok(None);
+        }
+        Ok(Some(turn))
+    }
+}
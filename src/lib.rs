@@ -0,0 +1,36 @@
+//! Chatbot on top of the candle Mistral, Llama, Qwen2, Phi-3 and Gemma models.
+//!
+//! Look up a model in the [`Registry`], load it with [`ModelBuilder`], wrap
+//! it in a [`TextGeneration`] and either stream the text of a single prompt
+//! with [`TextGeneration::stream`] or hold a whole conversation with
+//! [`ChatSession`].
+
+pub mod cancel;
+pub mod chat_model;
+pub mod chat_template;
+pub mod context;
+pub mod conversation;
+pub mod generation;
+pub mod logit_bias;
+pub mod logits;
+pub mod model;
+pub mod quantized;
+pub mod registry;
+pub mod sampling;
+pub mod stop;
+
+pub use cancel::CancelToken;
+pub use chat_model::{ChatModel, ModelConfig};
+pub use chat_template::ChatTemplate;
+pub use context::ContextBudget;
+pub use conversation::{Conversation, Message, Role};
+pub use generation::{
+    ChatSession, FinishReason, GenerationResult, GenerationStats, TextGeneration, Token,
+    TokenStream,
+};
+pub use logit_bias::LogitBias;
+pub use logits::{LogitsContext, LogitsPipeline, LogitsTransform};
+pub use model::{LoadedModel, ModelBuilder, ModelFiles};
+pub use registry::{Architecture, ModelSpec, Registry};
+pub use sampling::{Mirostat, SamplingConfig};
+pub use stop::StopSequences;
@@ -0,0 +1,83 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use tokenizers::Tokenizer;
+
+use candle_core::Tensor;
+
+use crate::logits::{map_logits, LogitsContext, LogitsTransform};
+
+/// Additive biases on the logits of some tokens, a bias of `-inf` bans the
+/// token.
+#[derive(Clone, Debug, Default)]
+pub struct LogitBias {
+    biases: HashMap<u32, f32>,
+}
+
+impl LogitBias {
+    pub fn new(biases: HashMap<u32, f32>) -> Self {
+        Self { biases }
+    }
+
+    /// Resolves the tokens, given either as token ids or as token strings of
+    /// the vocabulary, e.g. `"[INST]"`. A token string must be a single token,
+    /// it is not tokenized. A later bias for the same token replaces the
+    /// earlier one. The biases must be finite or `-inf`.
+    pub fn from_tokens<I, S>(tokenizer: &Tokenizer, biases: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (S, f32)>,
+        S: AsRef<str>,
+    {
+        let vocab_size = tokenizer.get_vocab_size(true);
+        let mut resolved = HashMap::new();
+        for (token, bias) in biases {
+            let token = token.as_ref();
+            if bias.is_nan() || bias == f32::INFINITY {
+                anyhow::bail!("invalid bias {bias} for '{token}', only -inf can ban a token")
+            }
+            let id = match token.parse::<u32>() {
+                Ok(id) if (id as usize) < vocab_size => id,
+                Ok(id) => anyhow::bail!("token id {id} is out of the vocabulary"),
+                Err(_) => match tokenizer.token_to_id(token) {
+                    Some(id) => id,
+                    None => anyhow::bail!("'{token}' is not a single token of the vocabulary"),
+                },
+            };
+            resolved.insert(id, bias);
+        }
+        Ok(Self::new(resolved))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.biases.is_empty()
+    }
+}
+
+impl LogitsTransform for LogitBias {
+    fn name(&self) -> &str {
+        "logit_bias"
+    }
+
+    fn apply(&mut self, logits: &Tensor, _context: &LogitsContext) -> Result<Tensor> {
+        if self.is_empty() {
+            return Ok(logits.clone());
+        }
+        map_logits(logits, |logits| {
+            for (&token, &bias) in self.biases.iter() {
+                if let Some(logit) = logits.get_mut(token as usize) {
+                    *logit += bias;
+                }
+            }
+        })
+    }
+}
+
+/// Parses a `TOKEN=BIAS` command line entry, e.g. `[INST]=-inf` or `1234=2.5`.
+pub fn parse_entry(entry: &str) -> Result<(String, f32)> {
+    match entry.rsplit_once('=') {
+        Some((token, bias)) if !token.is_empty() => match bias.trim().parse() {
+            Ok(bias) => Ok((token.to_string(), bias)),
+            Err(_) => anyhow::bail!("invalid bias '{bias}' for '{token}'"),
+        },
+        _ => anyhow::bail!("invalid logit bias '{entry}', expected TOKEN=BIAS"),
+    }
+}
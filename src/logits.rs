@@ -0,0 +1,275 @@
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+
+use candle_core::Tensor;
+
+/// What the transforms can look at besides the logits.
+#[derive(Clone, Debug, Copy)]
+pub struct LogitsContext<'t> {
+    /// The prompt tokens followed by the tokens generated so far.
+    pub tokens: &'t [u32],
+    pub prompt_tokens: usize,
+}
+
+impl LogitsContext<'_> {
+    /// The tokens generated so far.
+    pub fn generated(&self) -> &[u32] {
+        &self.tokens[self.prompt_tokens..]
+    }
+}
+
+/// A stage of the logits pipeline, e.g. a penalty, a sampling filter or a
+/// grammar mask. Masked tokens get a logit of `-inf`.
+pub trait LogitsTransform {
+    /// Name of the stage, unique within a pipeline.
+    fn name(&self) -> &str;
+
+    /// Transforms `logits`, a vector of f32 over the vocabulary.
+    fn apply(&mut self, logits: &Tensor, context: &LogitsContext) -> Result<Tensor>;
+
+    /// Called with the token sampled from the transformed logits, for the
+    /// stages adapting to the output.
+    fn accept(&mut self, _token: u32) {}
+}
+
+/// The logits transforms run in order before every sampling.
+#[derive(Default)]
+pub struct LogitsPipeline {
+    transforms: Vec<Box<dyn LogitsTransform>>,
+}
+
+impl LogitsPipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a stage, running after all the others.
+    pub fn push(&mut self, transform: Box<dyn LogitsTransform>) {
+        self.transforms.push(transform);
+    }
+
+    /// Inserts a stage at `index`, before the stage at that index.
+    pub fn insert(&mut self, index: usize, transform: Box<dyn LogitsTransform>) {
+        self.transforms.insert(index, transform);
+    }
+
+    /// Replaces the stage with the same name in place, appends it when there
+    /// is none.
+    pub fn replace(&mut self, transform: Box<dyn LogitsTransform>) {
+        match self.position(transform.name()) {
+            Some(index) => self.transforms[index] = transform,
+            None => self.transforms.push(transform),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Box<dyn LogitsTransform>> {
+        let index = self.position(name)?;
+        Some(self.transforms.remove(index))
+    }
+
+    /// Index of the stage called `name`.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.transforms.iter().position(|t| t.name() == name)
+    }
+
+    /// Names of the stages, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.transforms.iter().map(|t| t.name())
+    }
+
+    pub fn apply(&mut self, logits: &Tensor, context: &LogitsContext) -> Result<Tensor> {
+        let mut logits = logits.clone();
+        for transform in self.transforms.iter_mut() {
+            let _span = tracing::trace_span!("logits", name = transform.name()).entered();
+            logits = transform.apply(&logits, context)?;
+        }
+        Ok(logits)
+    }
+
+    pub fn accept(&mut self, token: u32) {
+        for transform in self.transforms.iter_mut() {
+            transform.accept(token);
+        }
+    }
+}
+
+/// Runs `f` on a copy of `logits` and returns the result as a tensor on the
+/// same device.
+pub fn map_logits<F: FnOnce(&mut [f32])>(logits: &Tensor, f: F) -> Result<Tensor> {
+    let mut values = logits.to_vec1::<f32>()?;
+    f(&mut values);
+    let len = values.len();
+    Ok(Tensor::from_vec(values, len, logits.device())?)
+}
+
+/// Divides the logits of the last `last_n` tokens by `penalty` when positive,
+/// multiplies them otherwise. 1. means no penalty.
+#[derive(Clone, Debug, Copy)]
+pub struct RepeatPenalty {
+    pub penalty: f32,
+    pub last_n: usize,
+}
+
+impl LogitsTransform for RepeatPenalty {
+    fn name(&self) -> &str {
+        "repeat_penalty"
+    }
+
+    fn apply(&mut self, logits: &Tensor, context: &LogitsContext) -> Result<Tensor> {
+        if self.penalty == 1. {
+            return Ok(logits.clone());
+        }
+        let start_at = context.tokens.len().saturating_sub(self.last_n);
+        Ok(candle_transformers::utils::apply_repeat_penalty(
+            logits,
+            self.penalty,
+            &context.tokens[start_at..],
+        )?)
+    }
+}
+
+/// OpenAI style penalties on the tokens already generated: `frequency` is
+/// subtracted for every occurrence and `presence` once. 0. disables them.
+#[derive(Clone, Debug, Copy)]
+pub struct FrequencyPenalty {
+    pub frequency: f32,
+    pub presence: f32,
+}
+
+impl LogitsTransform for FrequencyPenalty {
+    fn name(&self) -> &str {
+        "frequency_penalty"
+    }
+
+    fn apply(&mut self, logits: &Tensor, context: &LogitsContext) -> Result<Tensor> {
+        if self.frequency == 0. && self.presence == 0. {
+            return Ok(logits.clone());
+        }
+        let mut counts = HashMap::new();
+        for &token in context.generated() {
+            *counts.entry(token).or_insert(0usize) += 1;
+        }
+        map_logits(logits, |logits| {
+            for (token, count) in counts {
+                if let Some(logit) = logits.get_mut(token as usize) {
+                    *logit -= count as f32 * self.frequency + self.presence;
+                }
+            }
+        })
+    }
+}
+
+/// Only lets the allowed tokens through, e.g. the tokens a grammar accepts
+/// next, masking all the others. `None` allows every token.
+#[derive(Clone, Debug, Default)]
+pub struct TokenMask {
+    pub allowed: Option<HashSet<u32>>,
+}
+
+impl LogitsTransform for TokenMask {
+    fn name(&self) -> &str {
+        "token_mask"
+    }
+
+    fn apply(&mut self, logits: &Tensor, _context: &LogitsContext) -> Result<Tensor> {
+        let Some(allowed) = &self.allowed else {
+            return Ok(logits.clone());
+        };
+        map_logits(logits, |logits| {
+            for (token, logit) in logits.iter_mut().enumerate() {
+                if !allowed.contains(&(token as u32)) {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logit_bias::LogitBias;
+    use candle_core::Device;
+
+    fn logits(values: &[f32]) -> Tensor {
+        Tensor::new(values, &Device::Cpu).unwrap()
+    }
+
+    fn apply(transform: &mut dyn LogitsTransform, values: &[f32], tokens: &[u32]) -> Vec<f32> {
+        let context = LogitsContext {
+            tokens,
+            prompt_tokens: 1,
+        };
+        let logits = transform.apply(&logits(values), &context).unwrap();
+        logits.to_vec1().unwrap()
+    }
+
+    #[test]
+    fn repeat_penalty() {
+        let mut penalty = RepeatPenalty {
+            penalty: 2.,
+            last_n: 2,
+        };
+        // Token 0 is out of the last 2 tokens.
+        let values = apply(&mut penalty, &[2., 2., -2., 1.], &[0, 1, 2]);
+        assert_eq!(values, [2., 1., -4., 1.]);
+    }
+
+    #[test]
+    fn frequency_penalty() {
+        let mut penalty = FrequencyPenalty {
+            frequency: 1.,
+            presence: 0.5,
+        };
+        // Token 0 is only in the prompt.
+        let values = apply(&mut penalty, &[0., 0., 0.], &[0, 1, 1, 2]);
+        assert_eq!(values, [0., -2.5, -1.5]);
+    }
+
+    #[test]
+    fn logit_bias() {
+        let mut bias = LogitBias::new(HashMap::from([(1, f32::NEG_INFINITY), (2, 1.)]));
+        let values = apply(&mut bias, &[0., 0., 0.], &[0]);
+        assert_eq!(values, [0., f32::NEG_INFINITY, 1.]);
+    }
+
+    #[test]
+    fn token_mask() {
+        let mut mask = TokenMask::default();
+        assert_eq!(apply(&mut mask, &[1., 2., 3.], &[0]), [1., 2., 3.]);
+        mask.allowed = Some(HashSet::from([0, 2]));
+        let values = apply(&mut mask, &[1., 2., 3.], &[0]);
+        assert_eq!(values, [1., f32::NEG_INFINITY, 3.]);
+    }
+
+    #[test]
+    fn pipeline_insert_and_replace() {
+        let mut pipeline = LogitsPipeline::new();
+        pipeline.push(Box::new(RepeatPenalty {
+            penalty: 1.,
+            last_n: 64,
+        }));
+        pipeline.push(Box::new(TokenMask::default()));
+        pipeline.insert(1, Box::new(LogitBias::default()));
+        let names = ["repeat_penalty", "logit_bias", "token_mask"];
+        assert!(pipeline.names().eq(names));
+
+        // Replaced in place, whereas a new stage is appended.
+        pipeline.replace(Box::new(LogitBias::new(HashMap::from([(0, -1.)]))));
+        assert!(pipeline.names().eq(names));
+        pipeline.replace(Box::new(FrequencyPenalty {
+            frequency: 0.,
+            presence: 0.,
+        }));
+        assert_eq!(pipeline.position("frequency_penalty"), Some(3));
+        assert!(pipeline.remove("repeat_penalty").is_some());
+        assert_eq!(pipeline.position("logit_bias"), Some(0));
+
+        let context = LogitsContext {
+            tokens: &[],
+            prompt_tokens: 0,
+        };
+        let values = pipeline.apply(&logits(&[1., 1.]), &context).unwrap();
+        assert_eq!(values.to_vec1::<f32>().unwrap(), [0., 1.]);
+    }
+}
@@ -1,162 +1,61 @@
-//use hf_hub::RepoType;
-//use hf_hub::api::sync::Api;
-//use hf_hub::Repo;
-use hf_hub::{api::sync::Api, Repo, RepoType};
-use clap::Parser;
-use tokenizers::Tokenizer;
-use anyhow::{Error as E, Result};
-use std::io;
+use anyhow::Result;
 use std::io::Write;
-
-use candle_transformers::models::mistral::{Config, Model as Mistral};
-use candle_transformers::models::quantized_mistral::Model as QMistral;
-use candle_transformers::generation::LogitsProcessor;
-use candle_transformers::generation::Sampling;
-use candle_examples::token_output_stream::TokenOutputStream;
-use candle_core::{DType, Device, Tensor};
-use candle_nn::VarBuilder;
-
-enum Model {
-    Mistral(Mistral),
-    Quantized(QMistral),
-}
-
-struct TextGeneration<'a, 'b> {
-    model: &'a mut Model,
-    device: &'b Device,
-    tokenizer: TokenOutputStream,
-    logits_processor: LogitsProcessor,
-    repeat_penalty: f32,
-    repeat_last_n: usize,
+use std::sync::{Arc, Mutex};
+use tracing_chrome::ChromeLayerBuilder;
+use tracing_subscriber::prelude::*;
+
+use chatbot::{
+    logit_bias, ChatSession, ContextBudget, FinishReason, LogitBias, Mirostat, ModelBuilder,
+    Registry, SamplingConfig, StopSequences, TextGeneration,
+};
+
+mod command;
+mod config;
+mod input;
+mod server;
+mod session;
+use command::{Command, Setting};
+use input::Input;
+use server::Server;
+use session::Session;
+
+#[derive(clap::Subcommand, Debug)]
+enum Action {
+    /// Serve the OpenAI compatible /v1/chat/completions and /v1/completions
+    /// endpoints over HTTP.
+    Serve {
+        /// The address to listen on.
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        addr: String,
+    },
+    /// Inspect the configuration, layered from the built-in defaults, the
+    /// config file, the RCHATBOT_* environment variables and the flags.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
 }
 
-impl<'a, 'b, 'c> TextGeneration<'a, 'b> {
-    #[allow(clippy::too_many_arguments)]
-    fn new(
-        model: &'a mut Model,
-        tokenizer: &'c Tokenizer,
-        seed: u64,
-        temp: Option<f64>,
-        top_p: Option<f64>,
-        top_k: Option<usize>,
-        repeat_penalty: f32,
-        repeat_last_n: usize,
-        device: &'b Device,
-    ) -> Self {
-        let logits_processor = {
-            let temperature = temp.unwrap_or(0.);
-            let sampling = if temperature <= 0. {
-                Sampling::ArgMax
-            } else {
-                match (top_k, top_p) {
-                    (None, None) => Sampling::All { temperature },
-                    (Some(k), None) => Sampling::TopK { k, temperature },
-                    (None, Some(p)) => Sampling::TopP { p, temperature },
-                    (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
-                }
-            };
-            LogitsProcessor::from_sampling(seed, sampling)
-        };
-
-        TextGeneration {
-            model,
-            tokenizer: TokenOutputStream::new(tokenizer.clone()),
-            logits_processor,
-            repeat_penalty,
-            repeat_last_n,
-            device,
-        }
-    }
-
-    fn run(&mut self, prompt: &str, sample_len: usize) -> Result<()> {
-        use std::io::Write;
-        self.tokenizer.clear();
-        let mut tokens = self
-            .tokenizer
-            .tokenizer()
-            .encode(prompt, true)
-            .map_err(E::msg)?
-            .get_ids()
-            .to_vec();
-        for &t in tokens.iter() {
-            if let Some(t) = self.tokenizer.next_token(t)? {
-                print!("{t}")
-            }
-        }
-        std::io::stdout().flush()?;
-
-        let mut generated_tokens = 0usize;
-        let eos_token = match self.tokenizer.get_token("</s>") {
-            Some(token) => token,
-            None => anyhow::bail!("cannot find the </s> token"),
-        };
-        let start_gen = std::time::Instant::now();
-        for index in 0..sample_len {
-            let context_size = if index > 0 { 1 } else { tokens.len() };
-            let start_pos = tokens.len().saturating_sub(context_size);
-            let ctxt = &tokens[start_pos..];
-            let input = Tensor::new(ctxt, &self.device)?.unsqueeze(0)?;
-            let logits = match &mut self.model {
-                Model::Mistral(m) => m.forward(&input, start_pos)?,
-                Model::Quantized(m) => m.forward(&input, start_pos)?,
-            };
-            let logits = logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?;
-            let logits = if self.repeat_penalty == 1. {
-                logits
-            } else {
-                let start_at = tokens.len().saturating_sub(self.repeat_last_n);
-                candle_transformers::utils::apply_repeat_penalty(
-                    &logits,
-                    self.repeat_penalty,
-                    &tokens[start_at..],
-                )?
-            };
-
-            let next_token = self.logits_processor.sample(&logits)?;
-            tokens.push(next_token);
-            generated_tokens += 1;
-            if next_token == eos_token {
-                break;
-            }
-            if let Some(t) = self.tokenizer.next_token(next_token)? {
-                print!("{t}");
-                std::io::stdout().flush()?;
-            }
-        }
-        let dt = start_gen.elapsed();
-        if let Some(rest) = self.tokenizer.decode_rest().map_err(E::msg)? {
-            print!("{rest}");
-        }
-        std::io::stdout().flush()?;
-        println!(
-            "\n{generated_tokens} tokens generated ({:.2} token/s)",
-            generated_tokens as f64 / dt.as_secs_f64(),
-        );
-        Ok(())
-    }
+#[derive(clap::Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the effective value of every option and where it comes from.
+    Show,
 }
 
+/// How the statistics of every answer are printed.
 #[derive(Clone, Debug, Copy, PartialEq, Eq, clap::ValueEnum)]
-enum Which {
-    #[value(name = "7b-v0.1")]
-    Mistral7bV01,
-    #[value(name = "7b-v0.2")]
-    Mistral7bV02,
-    #[value(name = "7b-instruct-v0.1")]
-    Mistral7bInstructV01,
-    #[value(name = "7b-instruct-v0.2")]
-    Mistral7bInstructV02,
-    #[value(name = "7b-maths-v0.1")]
-    Mathstral7bV01,
-    #[value(name = "nemo-2407")]
-    MistralNemo2407,
-    #[value(name = "nemo-instruct-2407")]
-    MistralNemoInstruct2407,
+enum StatsFormat {
+    Text,
+    Json,
+    Off,
 }
 
-#[derive(Parser, Debug)]
+#[derive(clap::Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    action: Option<Action>,
+
     /// Run on CPU rather than on GPU.
     #[arg(long)]
     cpu: bool,
@@ -168,8 +67,9 @@ struct Args {
     #[arg(long)]
     use_flash_attn: bool,
 
-    #[arg(long, default_value = "")]
-    prompt: String,
+    /// Answer this prompt and exit instead of starting an interactive chat.
+    #[arg(long)]
+    prompt: Option<String>,
 
     /// The temperature used to generate samples.
     #[arg(long)]
@@ -183,6 +83,27 @@ struct Args {
     #[arg(long)]
     top_k: Option<usize>,
 
+    /// Drop the tokens less likely than this fraction of the most likely one.
+    #[arg(long)]
+    min_p: Option<f64>,
+
+    /// Locally typical sampling probability mass.
+    #[arg(long)]
+    typical_p: Option<f64>,
+
+    /// Tail-free sampling cutoff, 1. disables it.
+    #[arg(long)]
+    tfs_z: Option<f64>,
+
+    /// Target surprise of Mirostat v2 sampling, in bits, replaces top-k and
+    /// top-p.
+    #[arg(long)]
+    mirostat_tau: Option<f64>,
+
+    /// Learning rate of Mirostat v2 sampling.
+    #[arg(long, default_value_t = 0.1)]
+    mirostat_eta: f64,
+
     /// The seed to use when generating random samples.
     #[arg(long, default_value_t = 299792458)]
     seed: u64,
@@ -191,19 +112,29 @@ struct Args {
     #[arg(long, short = 'n', default_value_t = 10000)]
     sample_len: usize,
 
-    /// The model size to use.
+    /// The model alias from the registry, the built-in models.toml extended
+    /// by ~/.config/rchatbot/models.toml.
     #[arg(long, default_value = "7b-v0.1")]
-    which: Which,
+    which: String,
 
     #[arg(long)]
     model_id: Option<String>,
 
-    #[arg(long, default_value = "main")]
-    revision: String,
+    /// Revision of the model repository, the registry one by default.
+    #[arg(long)]
+    revision: Option<String>,
 
     #[arg(long)]
     tokenizer_file: Option<String>,
 
+    /// Use the Jinja chat template from tokenizer_config.json rather than the
+    /// built-in template for the model.
+    #[arg(long)]
+    jinja_template: bool,
+
+    #[arg(long)]
+    tokenizer_config_file: Option<String>,
+
     #[arg(long)]
     config_file: Option<String>,
 
@@ -213,6 +144,14 @@ struct Args {
     #[arg(long)]
     quantized: bool,
 
+    /// Never touch the network, the model files must be in the hf-hub cache.
+    #[arg(long)]
+    offline: bool,
+
+    /// Local directory holding the model files, in place of the hub.
+    #[arg(long)]
+    model_dir: Option<String>,
+
     /// Penalty to be applied for repeating tokens, 1. means no penalty.
     #[arg(long, default_value_t = 1.1)]
     repeat_penalty: f32,
@@ -221,19 +160,153 @@ struct Args {
     #[arg(long, default_value_t = 64)]
     repeat_last_n: usize,
 
+    /// Penalty subtracted from the logits for every occurrence of a token in
+    /// the answer so far, 0. means no penalty.
+    #[arg(long, default_value_t = 0.)]
+    frequency_penalty: f32,
+
+    /// Penalty subtracted once from the logits of the tokens already in the
+    /// answer, 0. means no penalty.
+    #[arg(long, default_value_t = 0.)]
+    presence_penalty: f32,
+
+    /// Add BIAS to the logits of TOKEN, given as `TOKEN=BIAS` where TOKEN is a
+    /// token id or a single token of the vocabulary and BIAS may be `-inf`,
+    /// can be repeated.
+    #[arg(long)]
+    logit_bias: Vec<String>,
+
+    /// Never generate this token, given as a token id or a single token of
+    /// the vocabulary, e.g. `[INST]`, can be repeated.
+    #[arg(long)]
+    ban: Vec<String>,
+
     /// Use the slower dmmv cuda kernel.
     #[arg(long)]
     force_dmmv: bool,
+
+    /// Read blank-line separated blocks rather than single lines as user turns.
+    #[arg(long)]
+    blocks: bool,
+
+    /// Stop generating when this string is produced, can be repeated.
+    #[arg(long)]
+    stop: Vec<String>,
+
+    /// Stop generating when this token id is produced, can be repeated.
+    #[arg(long)]
+    stop_token: Vec<u32>,
+
+    /// Print the generation statistics to stderr as text, as JSON, or not at
+    /// all.
+    #[arg(long, default_value = "text")]
+    stats: StatsFormat,
+
+    /// Session file to resume from, the conversation is saved back to it
+    /// after every turn. Its settings apply unless given as flags.
+    #[arg(long)]
+    session: Option<String>,
+}
+
+impl Args {
+    fn sampling(&self) -> SamplingConfig {
+        SamplingConfig {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            top_k: self.top_k,
+            min_p: self.min_p,
+            typical_p: self.typical_p,
+            tfs_z: self.tfs_z,
+            mirostat: self.mirostat_tau.map(|tau| Mirostat {
+                tau,
+                eta: self.mirostat_eta,
+            }),
+        }
+    }
+
+    /// The `--logit-bias` and `--ban` entries, by token id or string.
+    fn logit_bias(&self) -> Result<Vec<(String, f32)>> {
+        let mut biases = self
+            .logit_bias
+            .iter()
+            .map(|entry| logit_bias::parse_entry(entry))
+            .collect::<Result<Vec<_>>>()?;
+        biases.extend(
+            self.ban
+                .iter()
+                .map(|token| (token.clone(), f32::NEG_INFINITY)),
+        );
+        Ok(biases)
+    }
 }
 
-fn print_type_of<T> (_: &T) {
-    println!("{}", std::any::type_name::<T>());
+/// Prints the answer to `message` as it is generated, see
+/// `ChatSession::send_with`, along with the truncation notice and the
+/// statistics.
+fn answer(
+    chat: &mut ChatSession,
+    message: &str,
+    sample_len: usize,
+    stats: StatsFormat,
+) -> Result<()> {
+    let result = chat.send_with(message, sample_len, |token| {
+        print!("{}", token.text);
+        std::io::stdout().flush()?;
+        Ok(())
+    })?;
+    // Only the answer goes to stdout, the diagnostics go to stderr.
+    println!();
+    if let Some(fit) = chat.last_fit.filter(|fit| fit.dropped > 0) {
+        eprintln!(
+            "left the {} oldest messages out to fit the context window of {} tokens, \
+             the prompt is now {} tokens",
+            fit.dropped,
+            chat.budget.max_tokens(),
+            fit.prompt_tokens
+        );
+    }
+    if result.stats.finish_reason == FinishReason::Cancelled {
+        eprintln!("[interrupted]");
+    }
+    match stats {
+        StatsFormat::Text => eprintln!("{}", result.stats),
+        StatsFormat::Json => eprintln!("{}", serde_json::to_string(&result.stats)?),
+        StatsFormat::Off => {}
+    }
+    Ok(())
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let config = config::Config::load()?;
+    if let Some(Action::Config {
+        command: ConfigCommand::Show,
+    }) = &config.args.action
+    {
+        config.show();
+        return Ok(());
+    }
+    let explicit = config.explicit_args();
+    let mut args = config.args;
+
+    // The trace is written when the guard is dropped, the Ctrl-C handler
+    // drops it too since `exit` skips the destructors.
+    let trace = Arc::new(Mutex::new(if args.tracing {
+        let (chrome_layer, guard) = ChromeLayerBuilder::new().build();
+        tracing_subscriber::registry().with(chrome_layer).init();
+        Some(guard)
+    } else {
+        None
+    }));
+
+    let session = match &args.session {
+        Some(path) if std::path::Path::new(path).exists() => Some(Session::load(path)?),
+        _ => None,
+    };
+    if let Some(session) = &session {
+        session.apply(&mut args, &explicit);
+    }
 
-    println!(
+    eprintln!(
         "avx: {}, neon: {}, simd128: {}, f16c: {}",
         candle_core::utils::with_avx(),
         candle_core::utils::with_neon(),
@@ -242,121 +315,161 @@ fn main() -> Result<()> {
     );
 
     let t_start = std::time::Instant::now();
-    let api = Api::new()?;
-
-    // model_id
-    let model_id = match args.model_id {
-        Some(model_id) => model_id,
-        None => {
-            if args.quantized {
-                if args.which != Which::Mistral7bV01 {
-                    anyhow::bail!("only 7b-v0.1 is available as a quantized model for now")
-                }
-                "lmz/candle-mistral".to_string()
-            } else {
-                let name = match args.which {
-                    Which::Mistral7bV01 => "mistralai/Mistral-7B-v0.1",
-                    Which::Mistral7bV02 => "mistralai/Mistral-7B-v0.2",
-                    Which::Mistral7bInstructV01 => "mistralai/Mistral-7B-Instruct-v0.1",
-                    Which::Mistral7bInstructV02 => "mistralai/Mistral-7B-Instruct-v0.2",
-                    Which::Mathstral7bV01 => "mistralai/mathstral-7B-v0.1",
-                    Which::MistralNemo2407 => "mistralai/Mistral-Nemo-Base-2407",
-                    Which::MistralNemoInstruct2407 => "mistralai/Mistral-Nemo-Instruct-2407",
-                };
-                name.to_string()
-            }
-        }
-    };
-
-    // repo
-    let repo = api.repo(Repo::with_revision(
-        model_id,
-        RepoType::Model,
-        args.revision,
-    ));
-
-    // tokenizer_filename
-    let tokenizer_filename = match args.tokenizer_file {
-        Some(file) => std::path::PathBuf::from(file),
-        None => repo.get("tokenizer.json")?,
-    };
+    let registry = Registry::load()?;
+    let mut builder = ModelBuilder::new(registry.get(&args.which)?.clone())
+        .jinja_template(args.jinja_template)
+        .quantized(args.quantized)
+        .use_flash_attn(args.use_flash_attn)
+        .offline(args.offline);
+    if let Some(model_id) = &args.model_id {
+        builder = builder.model_id(model_id);
+    }
+    if let Some(revision) = &args.revision {
+        builder = builder.revision(revision);
+    }
+    if let Some(file) = &args.tokenizer_file {
+        builder = builder.tokenizer_file(file);
+    }
+    if let Some(file) = &args.config_file {
+        builder = builder.config_file(file);
+    }
+    if let Some(files) = &args.weight_files {
+        builder = builder.weight_files(files);
+    }
+    if let Some(file) = &args.tokenizer_config_file {
+        builder = builder.tokenizer_config_file(file);
+    }
+    if let Some(dir) = &args.model_dir {
+        builder = builder.model_dir(dir);
+    }
+    let files = builder.resolve()?;
+    eprintln!("retrieved the files in {:?}", t_start.elapsed());
 
-    // filenames
-    let filenames = match args.weight_files {
-        Some(files) => files
-            .split(',')
-            .map(std::path::PathBuf::from)
-            .collect::<Vec<_>>(),
-        None => {
-            if args.quantized {
-                vec![repo.get("model-q4k.gguf")?]
-            } else {
-                candle_examples::hub_load_safetensors(&repo, "model.safetensors.index.json")?
-            }
-        }
-    };
-    println!("retrieved the files in {:?}", t_start.elapsed());
-    
     let t_start = std::time::Instant::now();
-    // config
-    let config = match args.config_file {
-        Some(config_file) => serde_json::from_slice(&std::fs::read(config_file)?)?,
-        None => {
-            if args.quantized {
-                Config::config_7b_v0_1(args.use_flash_attn)
-            } else {
-                let config_file = repo.get("config.json")?;
-                serde_json::from_slice(&std::fs::read(config_file)?)?
+    let device = candle_examples::device(args.cpu)?;
+    let mut loaded = files.load(&device)?;
+    eprintln!("loaded the model in {:?}", t_start.elapsed());
+
+    let mut pipeline = TextGeneration::new(
+        loaded.model.as_mut(),
+        &loaded.tokenizer,
+        args.seed,
+        args.sampling(),
+        args.repeat_penalty,
+        args.repeat_last_n,
+        &device,
+    );
+
+    let template = files.template.clone();
+    let budget = ContextBudget::new(pipeline.model_config().max_position_embeddings);
+
+    let mut stop = files.stop_sequences();
+    stop.extend(args.stop.iter().cloned());
+    pipeline.set_stop_sequences(StopSequences::new(stop, args.stop_token.clone()));
+    pipeline.set_frequency_penalty(args.frequency_penalty, args.presence_penalty);
+    pipeline.set_logit_bias(LogitBias::from_tokens(
+        &loaded.tokenizer,
+        args.logit_bias()?,
+    )?);
+
+    // Ctrl-C stops the answer being generated, at the prompt it exits as usual.
+    let cancel = pipeline.cancel_token();
+    // A weak reference, so that returning from main still drops the guard.
+    let handler_trace = Arc::downgrade(&trace);
+    ctrlc::set_handler(move || {
+        if !cancel.cancel() {
+            if let Some(trace) = handler_trace.upgrade() {
+                drop(trace.lock().ok().and_then(|mut guard| guard.take()));
             }
+            std::process::exit(130)
         }
-    };
+    })?;
 
-    // device
-    let device = candle_examples::device(args.cpu)?;
+    if let Some(Action::Serve { addr }) = &args.action {
+        let mut server = Server::new(&mut pipeline, &files, &budget, &args);
+        return server.run(addr);
+    }
 
-    // model
-    let (mut model, device) = if args.quantized {
-        let filename = &filenames[0];
-        let vb =
-            candle_transformers::quantized_var_builder::VarBuilder::from_gguf(filename, &device)?;
-        let model = QMistral::new(&config, vb)?;
-        (Model::Quantized(model), device)
-    } else {
-        let dtype = if device.is_cuda() {
-            DType::BF16
-        } else {
-            DType::F32
-        };
-        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device)? };
-        let model = Mistral::new(&config, vb)?;
-        (Model::Mistral(model), device)
-    };
-    println!("loaded the model in {:?}", t_start.elapsed());
-
-    // tokenizer
-    let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
-    
-    let mut msg_in = String::new();
-    loop {
-        let mut pipeline = TextGeneration::new(
-            &mut model,
-            &tokenizer,
-            args.seed,
-            args.temperature,
-            args.top_p,
-            args.top_k,
-            args.repeat_penalty,
-            args.repeat_last_n,
-            &device,
-        );
+    let mut chat = ChatSession::new(&mut pipeline, template, budget);
+    if let Some(session) = &session {
+        chat.conversation = session.conversation();
+    }
 
-        msg_in.clear();
-        print!("> ");
-        std::io::stdout().flush().unwrap();
-        io::stdin().read_line(&mut msg_in).unwrap();
-        let msg_cpy = msg_in.clone();
-        //println!("echo {}", msg_cpy);
-        pipeline.run(&msg_cpy, args.sample_len)?;
+    if let Some(prompt) = &args.prompt {
+        answer(&mut chat, prompt, args.sample_len, args.stats)?;
+        if let Some(path) = &args.session {
+            Session::new(&args, &files, &chat.conversation).save(path)?;
+        }
+        return Ok(());
+    }
+
+    let mut input = Input::new(args.blocks);
+    while let Some(msg_in) = input.read_turn()? {
+        if let Some(command) = Command::parse(&msg_in) {
+            let command = match command {
+                Ok(command) => command,
+                Err(err) => {
+                    eprintln!("{err}");
+                    continue;
+                }
+            };
+            match command {
+                Command::Reset => chat.reset(),
+                Command::System(system) => chat.conversation.set_system(&system),
+                Command::Set(setting) => {
+                    match setting {
+                        Setting::Temperature(v) => args.temperature = v,
+                        Setting::TopP(v) => args.top_p = v,
+                        Setting::TopK(v) => args.top_k = v,
+                        Setting::MinP(v) => args.min_p = v,
+                        Setting::TypicalP(v) => args.typical_p = v,
+                        Setting::TfsZ(v) => args.tfs_z = v,
+                        Setting::MirostatTau(v) => args.mirostat_tau = v,
+                        Setting::MirostatEta(v) => args.mirostat_eta = v,
+                        Setting::RepeatPenalty(v) => args.repeat_penalty = v,
+                        Setting::RepeatLastN(v) => args.repeat_last_n = v,
+                        Setting::FrequencyPenalty(v) => args.frequency_penalty = v,
+                        Setting::PresencePenalty(v) => args.presence_penalty = v,
+                        Setting::SampleLen(v) => args.sample_len = v,
+                    }
+                    // Only the changed stage is replaced, the sampler keeps
+                    // its random state until the next /seed.
+                    match setting {
+                        Setting::RepeatPenalty(_) | Setting::RepeatLastN(_) => chat
+                            .generation
+                            .set_repeat_penalty(args.repeat_penalty, args.repeat_last_n),
+                        Setting::FrequencyPenalty(_) | Setting::PresencePenalty(_) => chat
+                            .generation
+                            .set_frequency_penalty(args.frequency_penalty, args.presence_penalty),
+                        Setting::SampleLen(_) => {}
+                        _ => chat.generation.set_sampling(args.sampling()),
+                    }
+                }
+                Command::Seed(seed) => {
+                    args.seed = seed;
+                    chat.generation.set_seed(args.seed);
+                }
+                Command::History => {
+                    for message in chat.conversation.messages().iter() {
+                        println!("[{}] {}", message.role.as_str(), message.content);
+                    }
+                }
+                Command::Save(path) => match path.as_ref().or(args.session.as_ref()) {
+                    Some(path) => Session::new(&args, &files, &chat.conversation).save(path)?,
+                    None => println!("no session file given, use /save <path>"),
+                },
+                Command::Quit => break,
+                Command::Help => println!("{}", command::HELP),
+            }
+            continue;
+        }
+        if let Err(err) = answer(&mut chat, &msg_in, args.sample_len, args.stats) {
+            eprintln!("{err}");
+            continue;
+        }
+        if let Some(path) = &args.session {
+            Session::new(&args, &files, &chat.conversation).save(path)?;
+        }
     }
     Ok(())
 }
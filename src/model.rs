@@ -0,0 +1,408 @@
+use anyhow::{Context, Error as E, Result};
+use hf_hub::{
+    api::sync::{Api, ApiRepo},
+    Cache, CacheRepo, Repo, RepoType,
+};
+use std::path::{Path, PathBuf};
+use tokenizers::Tokenizer;
+
+use candle_core::quantized::gguf_file;
+use candle_core::{DType, Device};
+use candle_nn::VarBuilder;
+use candle_transformers::models::{gemma, llama, mistral, phi3, quantized_mistral, qwen2};
+use candle_transformers::quantized_var_builder;
+
+use crate::chat_model::{ChatModel, Llama, ModelConfig, WithConfig};
+use crate::chat_template::ChatTemplate;
+use crate::quantized;
+use crate::registry::{Architecture, ModelSpec};
+
+/// Where the files of a model are looked up.
+enum Repository {
+    Hub(ApiRepo),
+    /// The hf-hub cache, never touches the network.
+    Cache(CacheRepo, String),
+    Dir(PathBuf),
+}
+
+impl Repository {
+    fn get(&self, file: &str) -> Result<PathBuf> {
+        match self {
+            Repository::Hub(repo) => Ok(repo.get(file)?),
+            Repository::Cache(repo, model_id) => match repo.get(file) {
+                Some(path) => Ok(path),
+                None => anyhow::bail!("{file} of {model_id} not found in the hf-hub cache"),
+            },
+            Repository::Dir(dir) => {
+                let path = dir.join(file);
+                if !path.exists() {
+                    anyhow::bail!("{} not found", path.display())
+                }
+                Ok(path)
+            }
+        }
+    }
+
+    /// Gets every shard listed in a safetensors index file.
+    fn safetensors(&self, index_file: &str) -> Result<Vec<PathBuf>> {
+        let index: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(self.get(index_file)?)?)?;
+        let weight_map = match index.get("weight_map").and_then(|m| m.as_object()) {
+            Some(weight_map) => weight_map,
+            None => anyhow::bail!("no weight_map in {index_file}"),
+        };
+        let mut shards = std::collections::BTreeSet::new();
+        for file in weight_map.values().filter_map(|file| file.as_str()) {
+            shards.insert(file);
+        }
+        shards.into_iter().map(|file| self.get(file)).collect()
+    }
+}
+
+/// Locates the files of a model, on the hub unless given explicitly.
+#[derive(Clone, Debug)]
+pub struct ModelBuilder {
+    spec: ModelSpec,
+    model_id: Option<String>,
+    revision: Option<String>,
+    tokenizer_file: Option<String>,
+    config_file: Option<String>,
+    weight_files: Option<String>,
+    tokenizer_config_file: Option<String>,
+    jinja_template: bool,
+    quantized: bool,
+    use_flash_attn: bool,
+    offline: bool,
+    model_dir: Option<PathBuf>,
+}
+
+impl ModelBuilder {
+    pub fn new(spec: ModelSpec) -> Self {
+        Self {
+            spec,
+            model_id: None,
+            revision: None,
+            tokenizer_file: None,
+            config_file: None,
+            weight_files: None,
+            tokenizer_config_file: None,
+            jinja_template: false,
+            quantized: false,
+            use_flash_attn: false,
+            offline: false,
+            model_dir: None,
+        }
+    }
+
+    pub fn model_id(mut self, model_id: impl Into<String>) -> Self {
+        self.model_id = Some(model_id.into());
+        self
+    }
+
+    pub fn revision(mut self, revision: impl Into<String>) -> Self {
+        self.revision = Some(revision.into());
+        self
+    }
+
+    pub fn tokenizer_file(mut self, file: impl Into<String>) -> Self {
+        self.tokenizer_file = Some(file.into());
+        self
+    }
+
+    pub fn config_file(mut self, file: impl Into<String>) -> Self {
+        self.config_file = Some(file.into());
+        self
+    }
+
+    /// Comma separated list of weight files.
+    pub fn weight_files(mut self, files: impl Into<String>) -> Self {
+        self.weight_files = Some(files.into());
+        self
+    }
+
+    pub fn tokenizer_config_file(mut self, file: impl Into<String>) -> Self {
+        self.tokenizer_config_file = Some(file.into());
+        self
+    }
+
+    /// Use the Jinja chat template from `tokenizer_config.json` rather than
+    /// the built-in template for the model.
+    pub fn jinja_template(mut self, jinja_template: bool) -> Self {
+        self.jinja_template = jinja_template;
+        self
+    }
+
+    pub fn quantized(mut self, quantized: bool) -> Self {
+        self.quantized = quantized;
+        self
+    }
+
+    pub fn use_flash_attn(mut self, use_flash_attn: bool) -> Self {
+        self.use_flash_attn = use_flash_attn;
+        self
+    }
+
+    /// Only use the files already in the hf-hub cache.
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Look up every file in a local directory rather than on the hub.
+    pub fn model_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.model_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    fn repository(&self, api: &Option<Api>, model_id: &str, revision: &str) -> Repository {
+        let repo = Repo::with_revision(model_id.to_string(), RepoType::Model, revision.to_string());
+        match (&self.model_dir, api) {
+            (Some(dir), _) => Repository::Dir(dir.clone()),
+            (None, Some(api)) => Repository::Hub(api.repo(repo)),
+            (None, None) => Repository::Cache(Cache::from_env().repo(repo), model_id.to_string()),
+        }
+    }
+
+    /// Resolves the model files, downloading them from the hub when needed
+    /// unless offline or given a model directory.
+    pub fn resolve(self) -> Result<ModelFiles> {
+        let _span = tracing::trace_span!("retrieve_files").entered();
+        let api = if self.offline || self.model_dir.is_some() {
+            None
+        } else {
+            Some(Api::new()?)
+        };
+
+        // local GGUF weights are always quantized
+        let quantized = self.quantized
+            || self
+                .weight_files
+                .as_deref()
+                .is_some_and(|files| files.ends_with(".gguf"));
+
+        // model_id, the GGUF repositories hold no tokenizer so it comes from
+        // the original model
+        let spec = &self.spec;
+        let gguf = match (&spec.gguf_repo, &spec.gguf_file) {
+            (Some(repo), Some(file)) => Some((repo.as_str(), file.as_str())),
+            _ if quantized && self.weight_files.is_none() => {
+                anyhow::bail!("no quantized weights for {}", spec.repo)
+            }
+            _ => None,
+        };
+        let model_id = match (&self.model_id, gguf) {
+            (Some(model_id), _) => model_id.clone(),
+            (None, Some((gguf_id, _))) if quantized => gguf_id.to_string(),
+            (None, _) => spec.repo.clone(),
+        };
+        let revision = match &self.revision {
+            Some(revision) => revision.clone(),
+            None if quantized => "main".to_string(),
+            None => spec.revision.clone(),
+        };
+
+        // repo
+        let repo = self.repository(&api, &model_id, &revision);
+        let base_repo = if quantized {
+            self.repository(&api, &spec.repo, &spec.revision)
+        } else {
+            self.repository(&api, &model_id, &revision)
+        };
+
+        // tokenizer
+        let tokenizer = match &self.tokenizer_file {
+            Some(file) => PathBuf::from(file),
+            None => base_repo.get(&spec.tokenizer_file)?,
+        };
+
+        // weights
+        let weights = match (&self.weight_files, gguf, &spec.weight_files) {
+            (Some(files), _, _) => files.split(',').map(PathBuf::from).collect::<Vec<_>>(),
+            (None, Some((_, gguf_file)), _) if quantized => vec![repo.get(gguf_file)?],
+            (None, _, Some(files)) => files
+                .iter()
+                .map(|file| repo.get(file))
+                .collect::<Result<Vec<_>>>()?,
+            (None, _, None) => repo.safetensors(&spec.weights_index)?,
+        };
+
+        // config, the quantized models read it from the GGUF metadata
+        let config = match &self.config_file {
+            Some(file) => Some(PathBuf::from(file)),
+            None if quantized => None,
+            None => Some(repo.get(&spec.config_file)?),
+        };
+
+        // chat template
+        let template = if self.jinja_template || spec.template == "jinja" {
+            let tokenizer_config = match &self.tokenizer_config_file {
+                Some(file) => PathBuf::from(file),
+                None => base_repo.get(&spec.tokenizer_config_file)?,
+            };
+            match ChatTemplate::from_tokenizer_config(tokenizer_config)? {
+                Some(template) => template,
+                None => anyhow::bail!("no chat_template found in tokenizer_config.json"),
+            }
+        } else {
+            ChatTemplate::from_name(&spec.template)?
+        };
+
+        Ok(ModelFiles {
+            spec: self.spec.clone(),
+            model_id,
+            revision,
+            tokenizer,
+            config,
+            weights,
+            template,
+            quantized,
+            use_flash_attn: self.use_flash_attn,
+        })
+    }
+}
+
+/// The files making up a model, ready to be loaded.
+#[derive(Clone, Debug)]
+pub struct ModelFiles {
+    pub spec: ModelSpec,
+    pub model_id: String,
+    pub revision: String,
+    pub tokenizer: PathBuf,
+    pub config: Option<PathBuf>,
+    pub weights: Vec<PathBuf>,
+    pub template: ChatTemplate,
+    pub quantized: bool,
+    pub use_flash_attn: bool,
+}
+
+/// A model with its tokenizer, loaded on a device.
+pub struct LoadedModel {
+    pub model: Box<dyn ChatModel>,
+    pub tokenizer: Tokenizer,
+}
+
+impl ModelFiles {
+    /// The stop sequences of the chat template and of the registry entry.
+    pub fn stop_sequences(&self) -> Vec<String> {
+        let mut stop = self.template.stop_sequences();
+        stop.extend(self.spec.stop_sequences.iter().cloned());
+        stop
+    }
+
+    pub fn load(&self, device: &Device) -> Result<LoadedModel> {
+        let _span = tracing::trace_span!("load_weights").entered();
+        // tokenizer
+        let tokenizer = Tokenizer::from_file(&self.tokenizer).map_err(E::msg)?;
+        let spec = &self.spec;
+        let eos_token_ids = spec
+            .eos_tokens
+            .iter()
+            .filter_map(|token| tokenizer.token_to_id(token))
+            .collect::<Vec<_>>();
+        let eos_token = match (spec.eos_tokens.first(), eos_token_ids.is_empty()) {
+            (Some(token), false) => token.clone(),
+            (Some(token), true) => anyhow::bail!("cannot find the {token} token"),
+            (None, _) => anyhow::bail!("no eos_tokens given for {}", spec.repo),
+        };
+        let model_config = |vocab_size, max_position_embeddings| ModelConfig {
+            vocab_size,
+            max_position_embeddings,
+            bos_token: spec.bos_token.clone(),
+            eos_token: eos_token.clone(),
+            eos_token_ids: eos_token_ids.clone(),
+        };
+
+        // model and config
+        let model: Box<dyn ChatModel> = if self.quantized {
+            if !matches!(
+                spec.architecture,
+                Architecture::Mistral | Architecture::Llama
+            ) {
+                anyhow::bail!("only the Mistral and Llama models can be quantized")
+            }
+            let mut file = std::fs::File::open(&self.weights[0])?;
+            let content =
+                gguf_file::Content::read(&mut file).map_err(|e| e.with_path(&self.weights[0]))?;
+            let candle_gguf = quantized::is_candle_gguf(&content);
+            let config: mistral::Config = match &self.config {
+                Some(config_file) => serde_json::from_slice(&std::fs::read(config_file)?)?,
+                // The candle conversions, from `lmz/candle-mistral`, have no
+                // metadata and are all of Mistral 7B v0.1.
+                None if candle_gguf => mistral::Config::config_7b_v0_1(self.use_flash_attn),
+                None => {
+                    let config = quantized::config_from_gguf(&content, self.use_flash_attn);
+                    config.with_context(|| {
+                        format!(
+                            "cannot derive the model config from {}, give a config file instead",
+                            self.weights[0].display()
+                        )
+                    })?
+                }
+            };
+            let model_config = model_config(config.vocab_size, config.max_position_embeddings);
+            if candle_gguf {
+                let vb = quantized_var_builder::VarBuilder::from_gguf(&self.weights[0], device)?;
+                let model = quantized_mistral::Model::new(&config, vb)?;
+                Box::new(WithConfig {
+                    model,
+                    config: model_config,
+                })
+            } else {
+                let model = quantized::Model::from_gguf(&config, &content, &mut file, device)?;
+                Box::new(WithConfig {
+                    model,
+                    config: model_config,
+                })
+            }
+        } else {
+            let config = match &self.config {
+                Some(config_file) => std::fs::read(config_file)?,
+                None => anyhow::bail!("no config file for {}", self.model_id),
+            };
+            let dtype = match &spec.dtype {
+                Some(dtype) => match dtype.parse::<DType>() {
+                    Ok(dtype) => dtype,
+                    Err(_) => anyhow::bail!("invalid dtype '{dtype}' for {}", spec.repo),
+                },
+                None if device.is_cuda() => DType::BF16,
+                None => DType::F32,
+            };
+            let vb = unsafe { VarBuilder::from_mmaped_safetensors(&self.weights, dtype, device)? };
+            match spec.architecture {
+                Architecture::Mistral => {
+                    let config: mistral::Config = serde_json::from_slice(&config)?;
+                    let model = mistral::Model::new(&config, vb)?;
+                    let config = model_config(config.vocab_size, config.max_position_embeddings);
+                    Box::new(WithConfig { model, config })
+                }
+                Architecture::Llama => {
+                    let config: llama::LlamaConfig = serde_json::from_slice(&config)?;
+                    let config = config.into_config(self.use_flash_attn);
+                    let model = llama::Llama::load(vb, &config)?;
+                    let info = model_config(config.vocab_size, config.max_position_embeddings);
+                    Box::new(Llama::new(model, &config, dtype, device, info)?)
+                }
+                Architecture::Qwen2 => {
+                    let config: qwen2::Config = serde_json::from_slice(&config)?;
+                    let model = qwen2::ModelForCausalLM::new(&config, vb)?;
+                    let config = model_config(config.vocab_size, config.max_position_embeddings);
+                    Box::new(WithConfig { model, config })
+                }
+                Architecture::Phi3 => {
+                    let config: phi3::Config = serde_json::from_slice(&config)?;
+                    let model = phi3::Model::new(&config, vb)?;
+                    let config = model_config(config.vocab_size, config.max_position_embeddings);
+                    Box::new(WithConfig { model, config })
+                }
+                Architecture::Gemma => {
+                    let config: gemma::Config = serde_json::from_slice(&config)?;
+                    let model = gemma::Model::new(self.use_flash_attn, &config, vb)?;
+                    let config = model_config(config.vocab_size, config.max_position_embeddings);
+                    Box::new(WithConfig { model, config })
+                }
+            }
+        };
+
+        Ok(LoadedModel { model, tokenizer })
+    }
+}
@@ -0,0 +1,412 @@
+//! Mistral model for the GGUF files converted by llama.cpp.
+//!
+//! Unlike `candle_transformers::models::quantized_mistral`, the tensors use
+//! the llama.cpp names and the interleaved rotary embedding layout, and the
+//! head dimension may differ from `hidden_size / num_attention_heads` as for
+//! Mistral Nemo.
+
+use anyhow::Result;
+use std::io::{Read, Seek};
+
+use candle_core::quantized::gguf_file::Content;
+use candle_core::quantized::QMatMul;
+use candle_core::{DType, Device, Module, Tensor, D};
+use candle_nn::Embedding;
+use candle_transformers::models::mistral::Config;
+use candle_transformers::quantized_nn::RmsNorm;
+
+/// Builds the model configuration from the `llama.*` or `mistral.*` keys of
+/// the GGUF metadata, depending on `general.architecture`.
+pub fn config_from_gguf(ct: &Content, use_flash_attn: bool) -> Result<Config> {
+    let arch = match ct.metadata.get("general.architecture") {
+        Some(v) => v.to_string()?.as_str(),
+        None => "llama",
+    };
+    if arch != "llama" && arch != "mistral" {
+        anyhow::bail!("unsupported gguf architecture '{arch}', expected llama or mistral")
+    }
+    let md_get = |key: &str| ct.metadata.get(&format!("{arch}.{key}"));
+    let required = |key: &str| match md_get(key) {
+        Some(v) => Ok(v),
+        None => anyhow::bail!("missing {arch}.{key} in the gguf metadata"),
+    };
+
+    let vocab_size = match md_get("vocab_size") {
+        Some(v) => v.to_u32()? as usize,
+        None => match ct.metadata.get("tokenizer.ggml.tokens") {
+            Some(tokens) => tokens.to_vec()?.len(),
+            None => anyhow::bail!(
+                "missing {arch}.vocab_size and tokenizer.ggml.tokens in the gguf metadata"
+            ),
+        },
+    };
+    let head_dim = match md_get("attention.key_length") {
+        Some(v) => Some(v.to_u32()? as usize),
+        None => None,
+    };
+    let rope_theta = match md_get("rope.freq_base") {
+        Some(v) => v.to_f32()? as f64,
+        None => 10_000.,
+    };
+    let sliding_window = match md_get("attention.sliding_window") {
+        Some(v) => Some(v.to_u32()? as usize),
+        None => None,
+    };
+
+    Ok(Config {
+        vocab_size,
+        hidden_size: required("embedding_length")?.to_u32()? as usize,
+        intermediate_size: required("feed_forward_length")?.to_u32()? as usize,
+        num_hidden_layers: required("block_count")?.to_u32()? as usize,
+        num_attention_heads: required("attention.head_count")?.to_u32()? as usize,
+        head_dim,
+        num_key_value_heads: required("attention.head_count_kv")?.to_u32()? as usize,
+        hidden_act: candle_nn::Activation::Silu,
+        max_position_embeddings: required("context_length")?.to_u32()? as usize,
+        rms_norm_eps: required("attention.layer_norm_rms_epsilon")?.to_f32()? as f64,
+        rope_theta,
+        sliding_window,
+        use_flash_attn,
+    })
+}
+
+/// Whether the tensors keep the safetensors names, e.g. `model.layers.0.*`,
+/// as in the GGUF files converted by candle. Those are loaded with
+/// `candle_transformers::models::quantized_mistral` instead.
+pub fn is_candle_gguf(ct: &Content) -> bool {
+    ct.tensor_infos.contains_key("model.embed_tokens.weight")
+}
+
+fn head_dim(cfg: &Config) -> usize {
+    cfg.head_dim
+        .unwrap_or(cfg.hidden_size / cfg.num_attention_heads)
+}
+
+/// Computes the rotary embedding of the positions as they are needed, the
+/// context length of some models is too large for a precomputed table.
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    inv_freq: Tensor,
+}
+
+impl RotaryEmbedding {
+    fn new(cfg: &Config, dev: &Device) -> Result<Self> {
+        let rope_theta = cfg.rope_theta as f32;
+        let dim = head_dim(cfg);
+        let inv_freq: Vec<_> = (0..dim)
+            .step_by(2)
+            .map(|i| 1f32 / rope_theta.powf(i as f32 / dim as f32))
+            .collect();
+        let inv_freq_len = inv_freq.len();
+        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+        Ok(Self { inv_freq })
+    }
+
+    fn apply_rotary_emb_qkv(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        seqlen_offset: usize,
+    ) -> Result<(Tensor, Tensor)> {
+        let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
+        let t = Tensor::arange(
+            seqlen_offset as u32,
+            (seqlen_offset + seq_len) as u32,
+            self.inv_freq.device(),
+        )?
+        .to_dtype(DType::F32)?
+        .reshape((seq_len, 1))?;
+        let freqs = t.matmul(&self.inv_freq)?;
+        let (cos, sin) = (freqs.cos()?, freqs.sin()?);
+        let q_embed = candle_nn::rotary_emb::rope_i(q, &cos, &sin)?;
+        let k_embed = candle_nn::rotary_emb::rope_i(k, &cos, &sin)?;
+        Ok((q_embed, k_embed))
+    }
+}
+
+/// Reads the tensors of a GGUF file.
+struct Weights<'a, R> {
+    ct: &'a Content,
+    reader: &'a mut R,
+    device: &'a Device,
+}
+
+impl<R: Seek + Read> Weights<'_, R> {
+    fn matmul(&mut self, name: &str) -> Result<QMatMul> {
+        let tensor = self.ct.tensor(self.reader, name, self.device)?;
+        Ok(QMatMul::from_qtensor(tensor)?)
+    }
+
+    fn rms_norm(&mut self, name: &str, eps: f64) -> Result<RmsNorm> {
+        let tensor = self.ct.tensor(self.reader, name, self.device)?;
+        Ok(RmsNorm::from_qtensor(tensor, eps)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(clippy::upper_case_acronyms)]
+struct MLP {
+    gate_proj: QMatMul,
+    up_proj: QMatMul,
+    down_proj: QMatMul,
+}
+
+impl Module for MLP {
+    fn forward(&self, xs: &Tensor) -> candle_core::Result<Tensor> {
+        let lhs = xs.apply(&self.gate_proj)?.silu()?;
+        let rhs = xs.apply(&self.up_proj)?;
+        (lhs * rhs)?.apply(&self.down_proj)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attention {
+    q_proj: QMatMul,
+    k_proj: QMatMul,
+    v_proj: QMatMul,
+    o_proj: QMatMul,
+    num_heads: usize,
+    num_kv_heads: usize,
+    num_kv_groups: usize,
+    head_dim: usize,
+    rotary_emb: std::sync::Arc<RotaryEmbedding>,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl Attention {
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let (b_sz, q_len, _) = xs.dims3()?;
+
+        let query_states = self.q_proj.forward(xs)?;
+        let key_states = self.k_proj.forward(xs)?;
+        let value_states = self.v_proj.forward(xs)?;
+
+        let query_states = query_states
+            .reshape((b_sz, q_len, self.num_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let key_states = key_states
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let value_states = value_states
+            .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
+            .transpose(1, 2)?;
+
+        let (query_states, key_states) =
+            self.rotary_emb
+                .apply_rotary_emb_qkv(&query_states, &key_states, seqlen_offset)?;
+
+        let (key_states, value_states) = match &self.kv_cache {
+            None => (key_states, value_states),
+            Some((prev_k, prev_v)) => {
+                let key_states = Tensor::cat(&[prev_k, &key_states], 2)?;
+                let value_states = Tensor::cat(&[prev_v, &value_states], 2)?;
+                (key_states, value_states)
+            }
+        };
+        self.kv_cache = Some((key_states.clone(), value_states.clone()));
+
+        let key_states = candle_transformers::utils::repeat_kv(key_states, self.num_kv_groups)?;
+        let value_states = candle_transformers::utils::repeat_kv(value_states, self.num_kv_groups)?;
+
+        let attn_output = {
+            let scale = 1f64 / f64::sqrt(self.head_dim as f64);
+            let attn_weights = (query_states.matmul(&key_states.transpose(2, 3)?)? * scale)?;
+
+            let attn_weights = match attention_mask {
+                None => attn_weights,
+                Some(mask) => attn_weights.broadcast_add(mask)?,
+            };
+            let attn_weights = candle_nn::ops::softmax_last_dim(&attn_weights)?;
+            attn_weights.matmul(&value_states)?
+        };
+        let attn_output = attn_output
+            .transpose(1, 2)?
+            .reshape((b_sz, q_len, self.num_heads * self.head_dim))?
+            .apply(&self.o_proj)?;
+        Ok(attn_output)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderLayer {
+    self_attn: Attention,
+    mlp: MLP,
+    input_layernorm: RmsNorm,
+    post_attention_layernorm: RmsNorm,
+}
+
+impl DecoderLayer {
+    fn forward(
+        &mut self,
+        xs: &Tensor,
+        attention_mask: Option<&Tensor>,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let residual = xs;
+        let xs = self.input_layernorm.forward(xs)?;
+        let xs = self.self_attn.forward(&xs, attention_mask, seqlen_offset)?;
+        let xs = (xs + residual)?;
+        let residual = &xs;
+        let xs = xs.apply(&self.post_attention_layernorm)?.apply(&self.mlp)?;
+        Ok((residual + xs)?)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Model {
+    embed_tokens: Embedding,
+    layers: Vec<DecoderLayer>,
+    norm: RmsNorm,
+    lm_head: QMatMul,
+    sliding_window: Option<usize>,
+    device: Device,
+}
+
+impl Model {
+    pub fn from_gguf<R: Seek + Read>(
+        cfg: &Config,
+        ct: &Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let mut w = Weights { ct, reader, device };
+        let eps = cfg.rms_norm_eps;
+        let head_dim = head_dim(cfg);
+
+        let embed_tokens = w.ct.tensor(w.reader, "token_embd.weight", device)?;
+        // Some models tie the output projection to the embeddings.
+        let lm_head = match w.ct.tensor(w.reader, "output.weight", device) {
+            Ok(tensor) => QMatMul::from_qtensor(tensor)?,
+            Err(_) => QMatMul::from_qtensor(w.ct.tensor(w.reader, "token_embd.weight", device)?)?,
+        };
+        let embed_tokens = Embedding::new(embed_tokens.dequantize(device)?, cfg.hidden_size);
+        let norm = w.rms_norm("output_norm.weight", eps)?;
+
+        let rotary_emb = std::sync::Arc::new(RotaryEmbedding::new(cfg, device)?);
+        let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
+        for layer_idx in 0..cfg.num_hidden_layers {
+            let prefix = format!("blk.{layer_idx}");
+            let self_attn = Attention {
+                q_proj: w.matmul(&format!("{prefix}.attn_q.weight"))?,
+                k_proj: w.matmul(&format!("{prefix}.attn_k.weight"))?,
+                v_proj: w.matmul(&format!("{prefix}.attn_v.weight"))?,
+                o_proj: w.matmul(&format!("{prefix}.attn_output.weight"))?,
+                num_heads: cfg.num_attention_heads,
+                num_kv_heads: cfg.num_key_value_heads,
+                num_kv_groups: cfg.num_attention_heads / cfg.num_key_value_heads,
+                head_dim,
+                rotary_emb: rotary_emb.clone(),
+                kv_cache: None,
+            };
+            let mlp = MLP {
+                gate_proj: w.matmul(&format!("{prefix}.ffn_gate.weight"))?,
+                up_proj: w.matmul(&format!("{prefix}.ffn_up.weight"))?,
+                down_proj: w.matmul(&format!("{prefix}.ffn_down.weight"))?,
+            };
+            layers.push(DecoderLayer {
+                self_attn,
+                mlp,
+                input_layernorm: w.rms_norm(&format!("{prefix}.attn_norm.weight"), eps)?,
+                post_attention_layernorm: w.rms_norm(&format!("{prefix}.ffn_norm.weight"), eps)?,
+            })
+        }
+
+        Ok(Self {
+            embed_tokens,
+            layers,
+            norm,
+            lm_head,
+            sliding_window: cfg.sliding_window,
+            device: device.clone(),
+        })
+    }
+
+    fn prepare_decoder_attention_mask(
+        &self,
+        tgt_len: usize,
+        seqlen_offset: usize,
+    ) -> Result<Tensor> {
+        let sliding_window = self.sliding_window.unwrap_or(tgt_len + 1);
+        let mask: Vec<_> = (0..tgt_len)
+            .flat_map(|i| {
+                (0..tgt_len).map(move |j| {
+                    if i < j || j + sliding_window < i {
+                        f32::NEG_INFINITY
+                    } else {
+                        0.
+                    }
+                })
+            })
+            .collect();
+        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
+        let mask = if seqlen_offset > 0 {
+            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
+            Tensor::cat(&[&mask0, &mask], D::Minus1)?
+        } else {
+            mask
+        };
+        Ok(mask.expand((1, 1, tgt_len, tgt_len + seqlen_offset))?)
+    }
+
+    pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
+        let (_b_size, seq_len) = input_ids.dims2()?;
+        let attention_mask = if seq_len <= 1 {
+            None
+        } else {
+            let mask = self.prepare_decoder_attention_mask(seq_len, seqlen_offset)?;
+            Some(mask)
+        };
+        let mut xs = self.embed_tokens.forward(input_ids)?;
+        for layer in self.layers.iter_mut() {
+            xs = layer.forward(&xs, attention_mask.as_ref(), seqlen_offset)?
+        }
+        let logits = xs
+            .narrow(1, seq_len - 1, 1)?
+            .contiguous()?
+            .apply(&self.norm)?
+            .apply(&self.lm_head)?;
+        Ok(logits)
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.self_attn.kv_cache = None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+    use std::io::Cursor;
+
+    /// A GGUF file holding a single tensor called `name`.
+    fn content(name: &str) -> Content {
+        let tensor = Tensor::zeros((2, 32), DType::F32, &Device::Cpu).unwrap();
+        let tensor = QTensor::quantize(&tensor, GgmlDType::F32).unwrap();
+        let mut buffer = Cursor::new(vec![]);
+        gguf_file::write(&mut buffer, &[], &[(name, &tensor)]).unwrap();
+        buffer.set_position(0);
+        Content::read(&mut buffer).unwrap()
+    }
+
+    #[test]
+    fn tensor_naming() {
+        assert!(is_candle_gguf(&content("model.embed_tokens.weight")));
+        assert!(!is_candle_gguf(&content("token_embd.weight")));
+    }
+
+    #[test]
+    fn missing_metadata() {
+        let err = config_from_gguf(&content("token_embd.weight"), false).unwrap_err();
+        assert!(err.to_string().contains("llama.vocab_size"), "{err}");
+    }
+}
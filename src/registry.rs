@@ -0,0 +1,134 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The model implementations from `candle_transformers`.
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Architecture {
+    Mistral,
+    Llama,
+    Qwen2,
+    Phi3,
+    Gemma,
+}
+
+fn default_revision() -> String {
+    "main".to_string()
+}
+
+fn default_tokenizer_file() -> String {
+    "tokenizer.json".to_string()
+}
+
+fn default_tokenizer_config_file() -> String {
+    "tokenizer_config.json".to_string()
+}
+
+fn default_config_file() -> String {
+    "config.json".to_string()
+}
+
+fn default_weights_index() -> String {
+    "model.safetensors.index.json".to_string()
+}
+
+/// Describes a model alias of the registry, see `models.toml` for the meaning
+/// of every field.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModelSpec {
+    pub repo: String,
+    #[serde(default = "default_revision")]
+    pub revision: String,
+    pub architecture: Architecture,
+    pub template: String,
+    #[serde(default)]
+    pub bos_token: String,
+    pub eos_tokens: Vec<String>,
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    pub dtype: Option<String>,
+    #[serde(default = "default_tokenizer_file")]
+    pub tokenizer_file: String,
+    #[serde(default = "default_tokenizer_config_file")]
+    pub tokenizer_config_file: String,
+    #[serde(default = "default_config_file")]
+    pub config_file: String,
+    #[serde(default = "default_weights_index")]
+    pub weights_index: String,
+    pub weight_files: Option<Vec<String>>,
+    pub gguf_repo: Option<String>,
+    pub gguf_file: Option<String>,
+}
+
+/// The model aliases, built-in ones along with the user ones.
+#[derive(Clone, Debug)]
+pub struct Registry {
+    models: BTreeMap<String, ModelSpec>,
+}
+
+/// Directory holding the user files, `$XDG_CONFIG_HOME/rchatbot` or
+/// `~/.config/rchatbot`.
+pub fn config_dir() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => dirs::home_dir()?.join(".config"),
+    };
+    Some(base.join("rchatbot"))
+}
+
+impl Registry {
+    /// The models shipped with the crate.
+    pub fn builtin() -> Self {
+        let models =
+            toml::from_str(include_str!("models.toml")).expect("the built-in models.toml is valid");
+        Self { models }
+    }
+
+    /// The built-in models, overridden and extended by the `models.toml` and
+    /// `models.json` files of the user config directory.
+    pub fn load() -> Result<Self> {
+        let mut registry = Self::builtin();
+        if let Some(dir) = config_dir() {
+            for file in ["models.toml", "models.json"] {
+                let path = dir.join(file);
+                if path.exists() {
+                    registry.merge_file(&path)?;
+                }
+            }
+        }
+        Ok(registry)
+    }
+
+    /// Adds the models of a TOML or JSON file, replacing the ones with the
+    /// same alias.
+    pub fn merge_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("cannot read model registry {}", path.display()))?;
+        let models: BTreeMap<String, ModelSpec> =
+            if path.extension().is_some_and(|ext| ext == "json") {
+                serde_json::from_str(&data).map_err(anyhow::Error::from)
+            } else {
+                toml::from_str(&data).map_err(anyhow::Error::from)
+            }
+            .with_context(|| format!("invalid model registry {}", path.display()))?;
+        self.models.extend(models);
+        Ok(())
+    }
+
+    pub fn get(&self, alias: &str) -> Result<&ModelSpec> {
+        match self.models.get(alias) {
+            Some(spec) => Ok(spec),
+            None => anyhow::bail!(
+                "unknown model '{alias}', expected one of {}",
+                self.aliases().collect::<Vec<_>>().join(", ")
+            ),
+        }
+    }
+
+    pub fn aliases(&self) -> impl Iterator<Item = &str> {
+        self.models.keys().map(|alias| alias.as_str())
+    }
+}
@@ -0,0 +1,433 @@
+use anyhow::Result;
+
+use candle_core::Tensor;
+
+use crate::logits::{map_logits, LogitsContext, LogitsTransform};
+
+/// Mirostat v2 settings, the sampler keeps the surprise of the generated
+/// tokens close to `tau`.
+#[derive(Clone, Debug, Copy, PartialEq)]
+pub struct Mirostat {
+    /// Target surprise, in bits.
+    pub tau: f64,
+    /// Learning rate of the truncation threshold.
+    pub eta: f64,
+}
+
+/// How the next token is picked from the logits.
+///
+/// The logits are divided by the temperature, then filtered by top-k,
+/// tail-free, typical, top-p, min-p and Mirostat sampling, see `transforms`.
+/// Mirostat replaces top-k and top-p. Without a temperature the most likely
+/// token is always picked.
+#[derive(Clone, Debug, Copy, PartialEq, Default)]
+pub struct SamplingConfig {
+    pub temperature: Option<f64>,
+    /// Nucleus sampling probability cutoff.
+    pub top_p: Option<f64>,
+    /// Only sample among the top K tokens.
+    pub top_k: Option<usize>,
+    /// Drops the tokens less likely than `min_p` times the most likely one.
+    pub min_p: Option<f64>,
+    /// Locally typical sampling probability mass.
+    pub typical_p: Option<f64>,
+    /// Tail-free sampling cutoff on the second derivative of the sorted
+    /// probabilities.
+    pub tfs_z: Option<f64>,
+    pub mirostat: Option<Mirostat>,
+}
+
+impl SamplingConfig {
+    /// Whether the most likely token is always picked.
+    pub fn is_greedy(&self) -> bool {
+        self.temperature.unwrap_or(0.) <= 0.
+    }
+
+    /// The sampling stages of the logits pipeline, in order. They are all
+    /// there, disabled when not set, so that they can be replaced in place.
+    pub fn transforms(&self) -> Vec<Box<dyn LogitsTransform>> {
+        // Greedy decoding picks the most likely token, the filters are off.
+        let config = if self.is_greedy() {
+            SamplingConfig::default()
+        } else {
+            *self
+        };
+        let (top_k, top_p) = match config.mirostat {
+            Some(_) => (None, None),
+            None => (config.top_k, config.top_p),
+        };
+        vec![
+            Box::new(Temperature(config.temperature)),
+            Box::new(TopK(top_k)),
+            Box::new(TailFree(config.tfs_z)),
+            Box::new(Typical(config.typical_p)),
+            Box::new(TopP(top_p)),
+            Box::new(MinP(config.min_p)),
+            Box::new(MirostatV2::new(config.mirostat)),
+        ]
+    }
+}
+
+/// Divides the logits by the temperature.
+#[derive(Clone, Debug, Copy)]
+pub struct Temperature(pub Option<f64>);
+
+impl LogitsTransform for Temperature {
+    fn name(&self) -> &str {
+        "temperature"
+    }
+
+    fn apply(&mut self, logits: &Tensor, _context: &LogitsContext) -> Result<Tensor> {
+        match self.0 {
+            Some(temperature) if temperature > 0. && temperature != 1. => {
+                Ok((logits / temperature)?)
+            }
+            _ => Ok(logits.clone()),
+        }
+    }
+}
+
+/// Defines a sampling filter stage, running `$filter` on the logits when its
+/// parameter is set.
+macro_rules! filter_transform {
+    ($(#[$doc:meta])* $transform:ident($param:ty), $name:literal, $filter:ident) => {
+        $(#[$doc])*
+        #[derive(Clone, Debug, Copy)]
+        pub struct $transform(pub Option<$param>);
+
+        impl LogitsTransform for $transform {
+            fn name(&self) -> &str {
+                $name
+            }
+
+            fn apply(&mut self, logits: &Tensor, _context: &LogitsContext) -> Result<Tensor> {
+                match self.0 {
+                    Some(param) => map_logits(logits, |logits| $filter(logits, param)),
+                    None => Ok(logits.clone()),
+                }
+            }
+        }
+    };
+}
+
+filter_transform!(
+    /// Only keeps the K most likely tokens.
+    TopK(usize),
+    "top_k",
+    top_k
+);
+filter_transform!(
+    /// Tail-free sampling with the given cutoff.
+    TailFree(f64),
+    "tail_free",
+    tail_free
+);
+filter_transform!(
+    /// Locally typical sampling with the given probability mass.
+    Typical(f64),
+    "typical_p",
+    typical
+);
+filter_transform!(
+    /// Nucleus sampling with the given probability cutoff.
+    TopP(f64),
+    "top_p",
+    top_p
+);
+filter_transform!(
+    /// Drops the tokens less likely than the given fraction of the most
+    /// likely one.
+    MinP(f64),
+    "min_p",
+    min_p
+);
+
+/// Mirostat v2, drops the tokens more surprising than a threshold adapted
+/// after every token so that the surprise stays close to the target.
+#[derive(Clone, Debug)]
+pub struct MirostatV2 {
+    mirostat: Option<Mirostat>,
+    /// Truncation threshold, in bits.
+    mu: f64,
+    /// Probabilities of the last logits, before the truncation.
+    probs: Vec<f32>,
+    /// Probability mass of the tokens kept by the last truncation.
+    kept_mass: f64,
+}
+
+impl MirostatV2 {
+    pub fn new(mirostat: Option<Mirostat>) -> Self {
+        Self {
+            mirostat,
+            mu: mirostat.map_or(0., |m| 2. * m.tau),
+            probs: vec![],
+            kept_mass: 1.,
+        }
+    }
+}
+
+impl LogitsTransform for MirostatV2 {
+    fn name(&self) -> &str {
+        "mirostat"
+    }
+
+    fn apply(&mut self, logits: &Tensor, _context: &LogitsContext) -> Result<Tensor> {
+        if self.mirostat.is_none() {
+            return Ok(logits.clone());
+        }
+        let mut values = logits.to_vec1::<f32>()?;
+        let probs = softmax(&values);
+        let kept = sorted_indices(&probs)
+            .into_iter()
+            .enumerate()
+            .take_while(|&(rank, i)| rank == 0 || -(probs[i] as f64).log2() <= self.mu)
+            .map(|(_, i)| i)
+            .collect::<Vec<_>>();
+        self.kept_mass = kept.iter().map(|&i| probs[i] as f64).sum::<f64>();
+        self.probs = probs;
+        mask_except(&mut values, &kept);
+        let len = values.len();
+        Ok(Tensor::from_vec(values, len, logits.device())?)
+    }
+
+    fn accept(&mut self, token: u32) {
+        let (Some(mirostat), Some(&p)) = (self.mirostat, self.probs.get(token as usize)) else {
+            return;
+        };
+        // The sampled token comes from the truncated distribution.
+        let surprise = -(p as f64 / self.kept_mass).log2();
+        self.mu -= mirostat.eta * (surprise - mirostat.tau);
+    }
+}
+
+/// Probabilities of `logits`, the masked logits get a zero probability.
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exps = logits.iter().map(|&l| (l - max).exp()).collect::<Vec<_>>();
+    let sum = exps.iter().sum::<f32>();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+/// Indices of the tokens with a non-zero probability, most likely first.
+fn sorted_indices(probs: &[f32]) -> Vec<usize> {
+    let mut indices = (0..probs.len())
+        .filter(|&i| probs[i] > 0.)
+        .collect::<Vec<_>>();
+    indices.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+    indices
+}
+
+/// The first tokens of `indices` until their probability mass reaches `p`,
+/// at least one.
+fn with_mass(indices: &[usize], probs: &[f32], p: f64) -> Vec<usize> {
+    let mut mass = 0.;
+    indices
+        .iter()
+        .enumerate()
+        .take_while(|&(rank, &i)| {
+            let below = rank == 0 || mass < p;
+            mass += probs[i] as f64;
+            below
+        })
+        .map(|(_, &i)| i)
+        .collect()
+}
+
+/// Masks every logit but the ones of `kept`.
+fn mask_except(logits: &mut [f32], kept: &[usize]) {
+    let mut keep = vec![false; logits.len()];
+    for &i in kept {
+        keep[i] = true;
+    }
+    for (logit, keep) in logits.iter_mut().zip(keep) {
+        if !keep {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+}
+
+/// Top-k sampling, masks all the tokens but the `k` most likely ones.
+fn top_k(logits: &mut [f32], k: usize) {
+    if k == 0 || k >= logits.len() {
+        return;
+    }
+    let mut indices = (0..logits.len()).collect::<Vec<_>>();
+    indices.select_nth_unstable_by(k - 1, |&a, &b| logits[b].total_cmp(&logits[a]));
+    mask_except(logits, &indices[..k]);
+}
+
+/// Nucleus sampling, keeps the most likely tokens until their probability
+/// mass reaches `p`.
+fn top_p(logits: &mut [f32], p: f64) {
+    if p >= 1. {
+        return;
+    }
+    let probs = softmax(logits);
+    let indices = sorted_indices(&probs);
+    mask_except(logits, &with_mass(&indices, &probs, p));
+}
+
+/// Min-p sampling, masks the tokens less likely than `p` times the most
+/// likely one.
+fn min_p(logits: &mut [f32], p: f64) {
+    if p <= 0. {
+        return;
+    }
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let threshold = max + p.min(1.).ln() as f32;
+    for logit in logits.iter_mut() {
+        if *logit < threshold {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+}
+
+/// Locally typical sampling, keeps the tokens whose surprise is the closest
+/// to the entropy of the distribution until their mass reaches `p`.
+fn typical(logits: &mut [f32], p: f64) {
+    if p >= 1. {
+        return;
+    }
+    let probs = softmax(logits);
+    let entropy = -probs
+        .iter()
+        .filter(|&&p| p > 0.)
+        .map(|&p| p as f64 * (p as f64).ln())
+        .sum::<f64>();
+    let mut indices = sorted_indices(&probs);
+    let distance = |i: usize| (-(probs[i] as f64).ln() - entropy).abs();
+    indices.sort_by(|&a, &b| distance(a).total_cmp(&distance(b)));
+    mask_except(logits, &with_mass(&indices, &probs, p));
+}
+
+/// Tail-free sampling, cuts the tail of the sorted probabilities where their
+/// curve flattens, that is once the normalized absolute second derivatives
+/// add up to `z`.
+fn tail_free(logits: &mut [f32], z: f64) {
+    if z >= 1. {
+        return;
+    }
+    let probs = softmax(logits);
+    let indices = sorted_indices(&probs);
+    if indices.len() <= 2 {
+        return;
+    }
+    let sorted = indices.iter().map(|&i| probs[i] as f64).collect::<Vec<_>>();
+    let first = sorted.windows(2).map(|w| w[0] - w[1]).collect::<Vec<_>>();
+    let second = first
+        .windows(2)
+        .map(|w| (w[0] - w[1]).abs())
+        .collect::<Vec<_>>();
+    let total = second.iter().sum::<f64>();
+    if total <= 0. {
+        return;
+    }
+    let mut mass = 0.;
+    let mut kept = indices.len();
+    for (i, d) in second.iter().enumerate() {
+        mass += d / total;
+        if mass > z && i >= 1 {
+            kept = i;
+            break;
+        }
+    }
+    mask_except(logits, &indices[..kept]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::Device;
+
+    /// Logits whose probabilities are `probs`.
+    fn logits(probs: &[f32]) -> Tensor {
+        let values = probs.iter().map(|p| p.ln()).collect::<Vec<_>>();
+        Tensor::new(values, &Device::Cpu).unwrap()
+    }
+
+    /// Indices of the tokens masked by `transform`.
+    fn masked(transform: &mut dyn LogitsTransform, probs: &[f32]) -> Vec<usize> {
+        let context = LogitsContext {
+            tokens: &[],
+            prompt_tokens: 0,
+        };
+        let logits = transform.apply(&logits(probs), &context).unwrap();
+        let values = logits.to_vec1::<f32>().unwrap();
+        (0..values.len())
+            .filter(|&i| values[i] == f32::NEG_INFINITY)
+            .collect()
+    }
+
+    const PROBS: [f32; 3] = [0.6, 0.3, 0.1];
+
+    #[test]
+    fn temperature() {
+        let context = LogitsContext {
+            tokens: &[],
+            prompt_tokens: 0,
+        };
+        let logits = Tensor::new(&[2f32, -4.], &Device::Cpu).unwrap();
+        let scaled = Temperature(Some(2.)).apply(&logits, &context).unwrap();
+        assert_eq!(scaled.to_vec1::<f32>().unwrap(), [1., -2.]);
+        let unchanged = Temperature(None).apply(&logits, &context).unwrap();
+        assert_eq!(unchanged.to_vec1::<f32>().unwrap(), [2., -4.]);
+    }
+
+    #[test]
+    fn top_k() {
+        assert_eq!(masked(&mut TopK(Some(2)), &[0.1, 0.4, 0.2, 0.3]), [0, 2]);
+        assert!(masked(&mut TopK(None), &[0.1, 0.4, 0.2, 0.3]).is_empty());
+    }
+
+    #[test]
+    fn top_p() {
+        assert_eq!(masked(&mut TopP(Some(0.5)), &PROBS), [1, 2]);
+        assert_eq!(masked(&mut TopP(Some(0.8)), &PROBS), [2]);
+        assert!(masked(&mut TopP(Some(1.)), &PROBS).is_empty());
+    }
+
+    #[test]
+    fn min_p() {
+        assert_eq!(masked(&mut MinP(Some(0.4)), &PROBS), [2]);
+        assert_eq!(masked(&mut MinP(Some(0.6)), &PROBS), [1, 2]);
+    }
+
+    #[test]
+    fn typical() {
+        // The entropy is 0.90 nats, the surprise of the second token, 1.20,
+        // is the closest to it, then the one of the first token, 0.51.
+        assert_eq!(masked(&mut Typical(Some(0.2)), &PROBS), [0, 2]);
+        assert_eq!(masked(&mut Typical(Some(0.5)), &PROBS), [2]);
+    }
+
+    #[test]
+    fn tail_free() {
+        // The normalized second derivatives are 0, 0.75 and 0.25.
+        let probs = [0.05, 0.3, 0.05, 0.5, 0.1];
+        assert_eq!(masked(&mut TailFree(Some(0.9)), &probs), [0, 2, 4]);
+        assert_eq!(masked(&mut TailFree(Some(0.5)), &probs), [0, 1, 2, 4]);
+    }
+
+    #[test]
+    fn mirostat() {
+        let mut mirostat = MirostatV2::new(Some(Mirostat { tau: 1., eta: 1. }));
+        // The threshold starts at 2 bits, the third token has 3.3 bits.
+        assert_eq!(masked(&mut mirostat, &PROBS), [2]);
+        // Sampling the second token, 1.58 bits in the truncated distribution,
+        // lowers the threshold below its 1.74 bits.
+        mirostat.accept(1);
+        assert_eq!(masked(&mut mirostat, &PROBS), [1, 2]);
+        assert!(masked(&mut MirostatV2::new(None), &PROBS).is_empty());
+    }
+
+    #[test]
+    fn greedy_disables_the_filters() {
+        let sampling = SamplingConfig {
+            top_k: Some(1),
+            ..Default::default()
+        };
+        for mut transform in sampling.transforms() {
+            assert!(masked(transform.as_mut(), &PROBS).is_empty());
+        }
+    }
+}
@@ -0,0 +1,476 @@
+use anyhow::{Error as E, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+
+use chatbot::context::ContextBudget;
+use chatbot::conversation::{Conversation, Message, Role};
+use chatbot::logit_bias::LogitBias;
+use chatbot::stop::StopSequences;
+use chatbot::{ChatSession, FinishReason, GenerationResult, ModelFiles, TextGeneration};
+
+use crate::Args;
+
+/// `stop` may be given either as a single string or as a list.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Fields shared by the chat and the plain completion requests.
+#[derive(Clone, Debug, Deserialize)]
+struct SamplingParams {
+    max_tokens: Option<usize>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    frequency_penalty: Option<f32>,
+    presence_penalty: Option<f32>,
+    /// Biases by token id or string, added to the command line ones.
+    logit_bias: Option<HashMap<String, f32>>,
+    seed: Option<u64>,
+    stop: Option<Stop>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ChatCompletionRequest {
+    messages: Vec<Message>,
+    #[serde(flatten)]
+    params: SamplingParams,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct CompletionRequest {
+    prompt: String,
+    #[serde(flatten)]
+    params: SamplingParams,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Usage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    total_tokens: usize,
+}
+
+#[derive(Debug)]
+struct HttpError {
+    status: u16,
+    message: String,
+}
+
+impl HttpError {
+    fn bad_request<M: std::fmt::Display>(message: M) -> Self {
+        Self {
+            status: 400,
+            message: message.to_string(),
+        }
+    }
+
+    fn not_found() -> Self {
+        Self {
+            status: 404,
+            message: "not found".to_string(),
+        }
+    }
+}
+
+impl From<E> for HttpError {
+    fn from(err: E) -> Self {
+        Self {
+            status: 500,
+            message: err.to_string(),
+        }
+    }
+}
+
+/// Serves the OpenAI `/v1/chat/completions` and `/v1/completions` endpoints
+/// with the already loaded model. Requests are answered one at a time.
+pub struct Server<'p, 'a, 'b> {
+    pipeline: &'p mut TextGeneration<'a, 'b>,
+    files: &'p ModelFiles,
+    budget: &'p ContextBudget,
+    args: &'p Args,
+}
+
+impl<'p, 'a, 'b> Server<'p, 'a, 'b> {
+    pub fn new(
+        pipeline: &'p mut TextGeneration<'a, 'b>,
+        files: &'p ModelFiles,
+        budget: &'p ContextBudget,
+        args: &'p Args,
+    ) -> Self {
+        Self {
+            pipeline,
+            files,
+            budget,
+            args,
+        }
+    }
+
+    pub fn run(&mut self, addr: &str) -> Result<()> {
+        let server = tiny_http::Server::http(addr).map_err(E::msg)?;
+        eprintln!("listening on http://{addr}");
+        for mut request in server.incoming_requests() {
+            let mut body = String::new();
+            if let Err(err) = request.as_reader().read_to_string(&mut body) {
+                eprintln!("cannot read request: {err}");
+                continue;
+            }
+            let method = request.method().clone();
+            let url = request.url().to_string();
+            eprintln!("{method} {url}");
+            let chat = match (method, url.as_str()) {
+                (tiny_http::Method::Post, "/v1/chat/completions") => true,
+                (tiny_http::Method::Post, "/v1/completions") => false,
+                (tiny_http::Method::Get, "/v1/models") => {
+                    respond(request, Ok(self.models()));
+                    continue;
+                }
+                _ => {
+                    respond(request, Err(HttpError::not_found()));
+                    continue;
+                }
+            };
+            if is_streaming(&body) {
+                if let Err(err) = self.stream(request, &body, chat) {
+                    eprintln!("cannot stream response: {err}");
+                }
+                continue;
+            }
+            let result = if chat {
+                self.chat_completion(&body, None)
+            } else {
+                self.completion(&body, None)
+            };
+            respond(request, result);
+        }
+        Ok(())
+    }
+
+    /// Sends the answer as server-sent events written straight to the socket
+    /// as the tokens are generated.
+    fn stream(&mut self, request: tiny_http::Request, body: &str, chat: bool) -> Result<()> {
+        let mut writer = start_event_stream(request)?;
+        let result = if chat {
+            self.chat_completion(body, Some(writer.as_mut()))
+        } else {
+            self.completion(body, Some(writer.as_mut()))
+        };
+        if let Err(err) = result {
+            send_event(writer.as_mut(), &error_body(&err.message))?;
+        }
+        Ok(())
+    }
+
+    fn models(&self) -> serde_json::Value {
+        serde_json::json!({
+            "object": "list",
+            "data": [{
+                "id": self.files.model_id,
+                "object": "model",
+                "owned_by": "rchatbot",
+            }],
+        })
+    }
+
+    fn chat_completion(
+        &mut self,
+        body: &str,
+        stream: Option<&mut dyn Write>,
+    ) -> Result<serde_json::Value, HttpError> {
+        let request: ChatCompletionRequest =
+            serde_json::from_str(body).map_err(HttpError::bad_request)?;
+        let mut conversation = Conversation::new();
+        for message in request.messages.iter() {
+            match message.role {
+                Role::System => conversation.set_system(&message.content),
+                role => conversation.push(role, &message.content),
+            }
+        }
+        if conversation.messages().last().map(|m| m.role) != Some(Role::User) {
+            return Err(HttpError::bad_request(
+                "the last message must be a user message",
+            ));
+        }
+
+        let sample_len = self.configure(&request.params, self.files.stop_sequences())?;
+        let (prompt, fit) = {
+            let template = self.files.template.clone();
+            let mut chat = ChatSession::new(&mut *self.pipeline, template, *self.budget);
+            chat.conversation = conversation;
+            chat.prompt(sample_len).map_err(HttpError::bad_request)?
+        };
+
+        let id = completion_id("chatcmpl");
+        let created = created();
+        let model = self.files.model_id.clone();
+        let result = match stream {
+            Some(writer) => {
+                let chunk = |delta: serde_json::Value, finish_reason: Option<&str>| {
+                    serde_json::json!({
+                        "id": id,
+                        "object": "chat.completion.chunk",
+                        "created": created,
+                        "model": model,
+                        "choices": [{
+                            "index": 0,
+                            "delta": delta,
+                            "finish_reason": finish_reason,
+                        }],
+                    })
+                };
+                send_event(
+                    writer,
+                    &chunk(serde_json::json!({ "role": "assistant" }), None),
+                )?;
+                let result = self.generate(&prompt, fit.sample_len, |text| {
+                    send_event(writer, &chunk(serde_json::json!({ "content": text }), None))
+                })?;
+                let finish_reason = finish_reason(result.stats.finish_reason);
+                send_event(writer, &chunk(serde_json::json!({}), Some(finish_reason)))?;
+                send_done(writer)?;
+                return Ok(serde_json::Value::Null);
+            }
+            None => self.generate(&prompt, fit.sample_len, |_| Ok(()))?,
+        };
+        Ok(serde_json::json!({
+            "id": id,
+            "object": "chat.completion",
+            "created": created,
+            "model": model,
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": result.text },
+                "finish_reason": finish_reason(result.stats.finish_reason),
+            }],
+            "usage": usage(&result),
+        }))
+    }
+
+    fn completion(
+        &mut self,
+        body: &str,
+        stream: Option<&mut dyn Write>,
+    ) -> Result<serde_json::Value, HttpError> {
+        let request: CompletionRequest =
+            serde_json::from_str(body).map_err(HttpError::bad_request)?;
+        let prompt = format!(
+            "{}{}",
+            self.pipeline.model_config().bos_token,
+            request.prompt
+        );
+        let prompt_tokens = self
+            .pipeline
+            .tokenizer()
+            .encode(prompt.as_str(), false)
+            .map_err(E::msg)?
+            .len();
+        if prompt_tokens == 0 {
+            return Err(HttpError::bad_request("the prompt is empty"));
+        }
+        // The raw completions are not cut at the chat template stops.
+        let sample_len = self.configure(&request.params, vec![])?;
+        let sample_len = match self.budget.max_tokens().checked_sub(prompt_tokens) {
+            Some(available) if available > 0 => sample_len.min(available),
+            _ => {
+                return Err(HttpError::bad_request(format!(
+                    "the prompt is {prompt_tokens} tokens long, the context window is {} tokens",
+                    self.budget.max_tokens()
+                )))
+            }
+        };
+
+        let id = completion_id("cmpl");
+        let created = created();
+        let model = self.files.model_id.clone();
+        let result = match stream {
+            Some(writer) => {
+                let chunk = |text: &str, finish_reason: Option<&str>| {
+                    serde_json::json!({
+                        "id": id,
+                        "object": "text_completion",
+                        "created": created,
+                        "model": model,
+                        "choices": [{
+                            "index": 0,
+                            "text": text,
+                            "finish_reason": finish_reason,
+                        }],
+                    })
+                };
+                let result = self.generate(&prompt, sample_len, |text| {
+                    send_event(writer, &chunk(text, None))
+                })?;
+                let finish_reason = finish_reason(result.stats.finish_reason);
+                send_event(writer, &chunk("", Some(finish_reason)))?;
+                send_done(writer)?;
+                return Ok(serde_json::Value::Null);
+            }
+            None => self.generate(&prompt, sample_len, |_| Ok(()))?,
+        };
+        Ok(serde_json::json!({
+            "id": id,
+            "object": "text_completion",
+            "created": created,
+            "model": model,
+            "choices": [{
+                "index": 0,
+                "text": result.text,
+                "finish_reason": finish_reason(result.stats.finish_reason),
+            }],
+            "usage": usage(&result),
+        }))
+    }
+
+    /// Applies the sampling parameters of a request on top of the command line
+    /// ones, returns the number of tokens to generate. `stop` holds the stop
+    /// sequences besides the ones of the request and of the command line.
+    fn configure(
+        &mut self,
+        params: &SamplingParams,
+        mut stop: Vec<String>,
+    ) -> Result<usize, HttpError> {
+        let args = self.args;
+        let mut sampling = args.sampling();
+        sampling.temperature = params.temperature.or(args.temperature);
+        sampling.top_p = params.top_p.or(args.top_p);
+        // Without a seed the sampler carries on, as between the turns of a chat.
+        if let Some(seed) = params.seed {
+            self.pipeline.set_seed(seed);
+        }
+        self.pipeline.set_sampling(sampling);
+        self.pipeline.set_frequency_penalty(
+            params.frequency_penalty.unwrap_or(args.frequency_penalty),
+            params.presence_penalty.unwrap_or(args.presence_penalty),
+        );
+        let mut biases = args.logit_bias()?;
+        if let Some(logit_bias) = &params.logit_bias {
+            biases.extend(logit_bias.clone());
+        }
+        let logit_bias = LogitBias::from_tokens(self.pipeline.tokenizer(), biases)
+            .map_err(HttpError::bad_request)?;
+        self.pipeline.set_logit_bias(logit_bias);
+        stop.extend(args.stop.iter().cloned());
+        match &params.stop {
+            Some(Stop::One(s)) => stop.push(s.clone()),
+            Some(Stop::Many(s)) => stop.extend(s.iter().cloned()),
+            None => {}
+        }
+        self.pipeline
+            .set_stop_sequences(StopSequences::new(stop, args.stop_token.clone()));
+        Ok(params.max_tokens.unwrap_or(args.sample_len))
+    }
+
+    /// Runs the generation, handing the text to `on_text`. A failure to send
+    /// the text to the client, e.g. because it went away, cancels the
+    /// generation.
+    fn generate<F>(
+        &mut self,
+        prompt: &str,
+        sample_len: usize,
+        mut on_text: F,
+    ) -> Result<GenerationResult>
+    where
+        F: FnMut(&str) -> Result<()>,
+    {
+        let cancel = self.pipeline.cancel_token();
+        self.pipeline.generate(prompt, sample_len, |token| {
+            if token.text.is_empty() {
+                return Ok(());
+            }
+            if let Err(err) = on_text(&token.text) {
+                eprintln!("cannot send to the client: {err}");
+                cancel.cancel();
+            }
+            Ok(())
+        })
+    }
+}
+
+fn usage(result: &GenerationResult) -> Usage {
+    let stats = &result.stats;
+    Usage {
+        prompt_tokens: stats.prompt_tokens,
+        completion_tokens: stats.generated_tokens,
+        total_tokens: stats.prompt_tokens + stats.generated_tokens,
+    }
+}
+
+/// OpenAI has no notion of a cancelled generation, the answer simply stops.
+fn finish_reason(finish_reason: FinishReason) -> &'static str {
+    match finish_reason {
+        FinishReason::Length => "length",
+        FinishReason::Eos | FinishReason::Stop | FinishReason::Cancelled => "stop",
+    }
+}
+
+fn respond(request: tiny_http::Request, result: Result<serde_json::Value, HttpError>) {
+    let (status, body) = match result {
+        Ok(body) => (200, body),
+        Err(err) => (err.status, error_body(&err.message)),
+    };
+    let response = tiny_http::Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(json_header());
+    if let Err(err) = request.respond(response) {
+        eprintln!("cannot send response: {err}");
+    }
+}
+
+fn is_streaming(body: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|v| v.get("stream").and_then(|s| s.as_bool()))
+        .unwrap_or(false)
+}
+
+fn json_header() -> tiny_http::Header {
+    tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .expect("valid header")
+}
+
+fn error_body(message: &str) -> serde_json::Value {
+    serde_json::json!({ "error": { "message": message } })
+}
+
+fn completion_id(prefix: &str) -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    format!("{prefix}-{nanos:x}")
+}
+
+fn created() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Takes over the connection to send server-sent events. tiny_http buffers
+/// chunked responses, so the headers are written by hand and every event is
+/// flushed as soon as it is produced.
+fn start_event_stream(request: tiny_http::Request) -> Result<Box<dyn Write + Send>> {
+    let mut writer = request.into_writer();
+    writer.write_all(
+        b"HTTP/1.1 200 OK\r\n\
+          Content-Type: text/event-stream\r\n\
+          Cache-Control: no-cache\r\n\
+          Connection: close\r\n\r\n",
+    )?;
+    writer.flush()?;
+    Ok(writer)
+}
+
+fn send_event(writer: &mut dyn Write, data: &serde_json::Value) -> Result<()> {
+    write!(writer, "data: {data}\n\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn send_done(writer: &mut dyn Write) -> Result<()> {
+    writer.write_all(b"data: [DONE]\n\n")?;
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,166 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeSet;
+
+use chatbot::conversation::{Conversation, Message, Role};
+use chatbot::ModelFiles;
+
+use crate::Args;
+
+/// The sessions saved before Mirostat was added use the default rate.
+fn default_mirostat_eta() -> f64 {
+    0.1
+}
+
+/// A conversation saved to disk together with everything needed to
+/// reproduce it: the model, the sampling parameters and the seed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub which: String,
+    pub model_id: String,
+    /// The resolved revision, only missing from the older sessions.
+    pub revision: Option<String>,
+    /// Whether `model_id` holds the GGUF weights.
+    #[serde(default)]
+    pub quantized: bool,
+    pub seed: u64,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    #[serde(default)]
+    pub min_p: Option<f64>,
+    #[serde(default)]
+    pub typical_p: Option<f64>,
+    #[serde(default)]
+    pub tfs_z: Option<f64>,
+    #[serde(default)]
+    pub mirostat_tau: Option<f64>,
+    #[serde(default = "default_mirostat_eta")]
+    pub mirostat_eta: f64,
+    pub repeat_penalty: f32,
+    pub repeat_last_n: usize,
+    #[serde(default)]
+    pub frequency_penalty: f32,
+    #[serde(default)]
+    pub presence_penalty: f32,
+    /// `TOKEN=BIAS` entries, as given to `--logit-bias`.
+    #[serde(default)]
+    pub logit_bias: Vec<String>,
+    #[serde(default)]
+    pub ban: Vec<String>,
+    pub sample_len: usize,
+    pub system_prompt: Option<String>,
+    pub messages: Vec<Message>,
+}
+
+impl Session {
+    pub fn new(args: &Args, files: &ModelFiles, conversation: &Conversation) -> Self {
+        let system_prompt = conversation
+            .messages()
+            .iter()
+            .find(|m| m.role == Role::System)
+            .map(|m| m.content.clone());
+        let messages = conversation
+            .messages()
+            .iter()
+            .filter(|m| m.role != Role::System)
+            .cloned()
+            .collect();
+        Self {
+            which: args.which.clone(),
+            model_id: files.model_id.clone(),
+            revision: Some(files.revision.clone()),
+            quantized: files.quantized,
+            seed: args.seed,
+            temperature: args.temperature,
+            top_p: args.top_p,
+            top_k: args.top_k,
+            min_p: args.min_p,
+            typical_p: args.typical_p,
+            tfs_z: args.tfs_z,
+            mirostat_tau: args.mirostat_tau,
+            mirostat_eta: args.mirostat_eta,
+            repeat_penalty: args.repeat_penalty,
+            repeat_last_n: args.repeat_last_n,
+            frequency_penalty: args.frequency_penalty,
+            presence_penalty: args.presence_penalty,
+            logit_bias: args.logit_bias.clone(),
+            ban: args.ban.clone(),
+            sample_len: args.sample_len,
+            system_prompt,
+            messages,
+        }
+    }
+
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data = std::fs::read(path)
+            .with_context(|| format!("cannot read session file {}", path.display()))?;
+        let session = serde_json::from_slice(&data)
+            .with_context(|| format!("invalid session file {}", path.display()))?;
+        Ok(session)
+    }
+
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("cannot write session file {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Overrides the model and sampling arguments with the saved ones, except
+    /// for the `explicit` ones given on the command line or through the
+    /// environment. The saved model is kept as a whole unless another one is
+    /// asked for.
+    pub fn apply(&self, args: &mut Args, explicit: &BTreeSet<String>) {
+        macro_rules! restore {
+            ($($field:ident),*) => {
+                $(
+                    if !explicit.contains(stringify!($field)) {
+                        args.$field = self.$field.clone();
+                    }
+                )*
+            };
+        }
+
+        let other_model = ["which", "model_id", "quantized"]
+            .iter()
+            .any(|id| explicit.contains(*id));
+        if !other_model {
+            args.which = self.which.clone();
+            args.model_id = Some(self.model_id.clone());
+            args.quantized = self.quantized;
+            restore!(revision);
+        }
+        restore!(
+            seed,
+            temperature,
+            top_p,
+            top_k,
+            min_p,
+            typical_p,
+            tfs_z,
+            mirostat_tau,
+            mirostat_eta,
+            repeat_penalty,
+            repeat_last_n,
+            frequency_penalty,
+            presence_penalty,
+            logit_bias,
+            ban,
+            sample_len
+        );
+    }
+
+    pub fn conversation(&self) -> Conversation {
+        let mut conversation = Conversation::new();
+        if let Some(system_prompt) = &self.system_prompt {
+            conversation.set_system(system_prompt);
+        }
+        for message in self.messages.iter() {
+            conversation.push(message.role, &message.content);
+        }
+        conversation
+    }
+}
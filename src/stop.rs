@@ -0,0 +1,95 @@
+/// Strings and token ids that end the generation when the model emits them.
+#[derive(Clone, Debug, Default)]
+pub struct StopSequences {
+    sequences: Vec<String>,
+    token_ids: Vec<u32>,
+}
+
+impl StopSequences {
+    pub fn new(sequences: Vec<String>, token_ids: Vec<u32>) -> Self {
+        let sequences = sequences.into_iter().filter(|s| !s.is_empty()).collect();
+        Self {
+            sequences,
+            token_ids,
+        }
+    }
+
+    pub fn is_stop_token(&self, token: u32) -> bool {
+        self.token_ids.contains(&token)
+    }
+
+    /// Returns the byte offset of the earliest stop sequence in `text`.
+    pub fn find(&self, text: &str) -> Option<usize> {
+        self.sequences
+            .iter()
+            .filter_map(|s| text.find(s.as_str()))
+            .min()
+    }
+
+    /// Cuts `text` just before the earliest stop sequence found after byte
+    /// `from`, the part already output. Returns whether there was one.
+    pub fn truncate(&self, text: &mut String, from: usize) -> bool {
+        match self.find(&text[from..]) {
+            Some(pos) => {
+                text.truncate(from + pos);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the length of the longest suffix of `text` that could be the
+    /// start of a stop sequence. That part must be held back from the output
+    /// until the following tokens tell whether the sequence completes.
+    pub fn partial_len(&self, text: &str) -> usize {
+        text.char_indices()
+            .map(|(i, _)| &text[i..])
+            .find(|suffix| self.sequences.iter().any(|s| s.starts_with(suffix)))
+            .map_or(0, |suffix| suffix.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop() -> StopSequences {
+        StopSequences::new(vec!["\nUser:".into(), "###".into(), "".into()], vec![2])
+    }
+
+    #[test]
+    fn find() {
+        let stop = stop();
+        assert_eq!(stop.find("Hi ### there\nUser:"), Some(3));
+        assert_eq!(stop.find("Hi\nUser"), None);
+        assert_eq!(stop.find(""), None);
+        assert!(stop.is_stop_token(2));
+        assert!(!stop.is_stop_token(3));
+    }
+
+    #[test]
+    fn partial_len() {
+        let stop = stop();
+        assert_eq!(stop.partial_len("Hi\nUs"), 3);
+        assert_eq!(stop.partial_len("Hi ##"), 2);
+        assert_eq!(stop.partial_len("Hi\n"), 1);
+        assert_eq!(stop.partial_len("Hi"), 0);
+        assert_eq!(stop.partial_len("Hé"), 0);
+        // The empty sequence was dropped, it would match any suffix.
+        assert_eq!(StopSequences::default().partial_len("Hi"), 0);
+    }
+
+    #[test]
+    fn truncate() {
+        let stop = stop();
+        let mut text = "Sure.\nUser: more".to_string();
+        assert!(stop.truncate(&mut text, 2));
+        assert_eq!(text, "Sure.");
+        let mut text = "Sure.\nUser".to_string();
+        assert!(!stop.truncate(&mut text, 0));
+        assert_eq!(text, "Sure.\nUser");
+        // Only the text after `from` is searched.
+        let mut text = "a###b".to_string();
+        assert!(!stop.truncate(&mut text, 2));
+    }
+}